@@ -1,4 +1,5 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use uuid::Uuid;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -21,10 +22,158 @@ pub enum Commands {
         ///Path to the config file
         #[arg(short, long, default_value = "gregory.toml")]
         config: String,
-        /* Not yet supported
+        ///Run every job, even ones that are up-to-date
         #[arg(short, long)]
-        daemonize: bool,
-        */
+        force: bool,
+        ///Run this job even if it's up-to-date; can be passed multiple times
+        #[arg(long = "force-job", value_name = "JOB_ID")]
+        force_jobs: Vec<String>,
+        ///Don't use a database; write run metadata to JSON lines files in the data dir instead
+        #[arg(long)]
+        no_db: bool,
+        ///Carry on with an earlier run, only running the jobs that didn't succeed in it
+        #[arg(long, value_name = "RUN_UUID")]
+        resume: Option<Uuid>,
+        #[command(flatten)]
+        selection: JobSelection,
+    },
+    ///Stays running, and runs jobs on the schedules set in the config
+    Daemon {
+        ///Path to the config file
+        #[arg(short, long, default_value = "gregory.toml")]
+        config: String,
+    },
+    ///Checks the config for problems, without running anything
+    Validate {
+        ///Path to the config file
+        #[arg(short, long, default_value = "gregory.toml")]
+        config: String,
+    },
+    ///Shows what would be run, in what order, and how, without running anything
+    Plan {
+        ///Path to the config file
+        #[arg(short, long, default_value = "gregory.toml")]
+        config: String,
+        ///How to print it
+        #[arg(long, value_enum, default_value_t = PlanFormat::Text)]
+        format: PlanFormat,
+        #[command(flatten)]
+        selection: JobSelection,
+    },
+    ///Shows the latest status of every job
+    Status {
+        ///Path to the config file
+        #[arg(short, long, default_value = "gregory.toml")]
+        config: String,
+        ///How to print it
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    ///Shows the last runs of a job, newest first
+    History {
+        ///The job's id, e.g. `packages.librewolf.compilation`
+        job_id: String,
+        ///Path to the config file
+        #[arg(short, long, default_value = "gregory.toml")]
+        config: String,
+        ///How many runs of it to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: i64,
+        ///How to print it
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    ///Prints a job's output, from the latest run of it by default
+    Logs {
+        ///The job's id, e.g. `packages.librewolf.compilation`
+        job_id: String,
+        ///Path to the config file
+        #[arg(short, long, default_value = "gregory.toml")]
+        config: String,
+        ///Show the output from this run; either the uuid of the run, or of the job's own row
+        #[arg(long, value_name = "UUID")]
+        run: Option<Uuid>,
+        ///Show the output from the latest run with this revision
+        #[arg(long)]
+        revision: Option<String>,
+        ///Keep printing output as it's written, until the job finishes
+        #[arg(short, long)]
+        follow: bool,
+        ///Only show output from this stream
+        #[arg(long, value_parser = ["stdout", "stderr"])]
+        stream: Option<String>,
+    },
+    ///Lists the last runs, newest first
+    Runs {
+        ///Path to the config file
+        #[arg(short, long, default_value = "gregory.toml")]
+        config: String,
+        ///How many runs to list
+        #[arg(short = 'n', long, default_value_t = 10)]
+        limit: i64,
+        ///How to print it
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    ///Manages the database
+    Db {
+        ///Path to the config file
+        #[arg(short, long, default_value = "gregory.toml", global = true)]
+        config: String,
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+}
+
+/// Which jobs to run; everything is run if no packages, distros, or jobs are passed
+#[derive(Args, Clone, Debug, Default)]
+pub struct JobSelection {
+    ///Only run this package's jobs, and the `update-repo` jobs for the distros it's packaged for; can be passed multiple times
+    #[arg(long = "package", value_name = "PACKAGE")]
+    pub packages: Vec<String>,
+    ///Only run the packaging jobs for this distro, and its `update-repo` job; can be passed multiple times
+    #[arg(long = "distro", value_name = "DISTRO")]
+    pub distros: Vec<String>,
+    ///Only run this job; can be passed multiple times
+    #[arg(long = "job", value_name = "JOB_ID")]
+    pub jobs: Vec<String>,
+    ///Also run everything the selected jobs depend on
+    #[arg(long)]
+    pub with_deps: bool,
+    ///Also run everything that depends on the selected jobs
+    #[arg(long)]
+    pub with_dependents: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    ///A table, with the columns lined up
+    Table,
+    ///A JSON array
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum PlanFormat {
+    ///A list of the jobs, with their details
+    Text,
+    ///A JSON array
+    Json,
+    ///A Graphviz DOT graph of the jobs and their dependencies
+    Dot,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommands {
+    ///Migrates the database to the latest schema version
+    Migrate,
+    ///Shows the database's schema version, and which migrations have been applied
+    Status,
+    ///Imports the JSON lines files written by `run --no-db` into the database
+    Import {
+        ///The directory with the files in it; default: `no-db` in the data dir
+        #[arg(long)]
+        from: Option<String>,
     },
 }
 
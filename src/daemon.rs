@@ -0,0 +1,124 @@
+//! Daemon mode - stays running, and runs jobs on the schedules set in the config
+
+use crate::cli::JobSelection;
+use crate::data::Config;
+use crate::errors::Error;
+use crate::executor::{JobLocks, RunOptions, Trigger};
+use crate::run;
+use crate::shutdown::{self, Cancellation};
+use chrono::Local;
+use cron::Schedule;
+use std::collections::HashMap;
+use std::str::FromStr;
+use tokio::task::JoinHandle;
+
+/// Runs forever, starting a run whenever one of the schedules in the config comes up
+///
+/// The top-level `schedule` runs everything, and each package's `schedule` runs just that package's jobs (see [`State::package_jobs`](crate::State::package_jobs)). A schedule is skipped if its last run is still going, and jobs that are already running in another run are waited on rather than run twice at once.
+///
+/// On SIGINT/SIGTERM, it stops scheduling runs, cancels the ones that are going, and exits once they've finished
+pub(crate) async fn daemon(config_path: String) -> Result<(), Error> {
+    let conf = Config::from_file(config_path.clone())?;
+
+    // the package each schedule is for, or None for everything
+    let mut schedules: Vec<(Option<String>, Schedule)> = Vec::new();
+    if let Some(schedule) = &conf.schedule {
+        schedules.push((None, parse_schedule(schedule)?));
+    }
+    let mut package_names: Vec<&String> = conf.packages.keys().collect();
+    package_names.sort();
+    for package_name in package_names {
+        if let Some(schedule) = &conf.packages[package_name].schedule {
+            schedules.push((Some(package_name.clone()), parse_schedule(schedule)?));
+        }
+    }
+    if schedules.is_empty() {
+        return Err(Error::NoSchedules);
+    }
+
+    println!(
+        "Started gregory daemon with {} schedule(s)",
+        schedules.len()
+    );
+
+    let job_locks = JobLocks::default();
+    let cancellation = Cancellation::default();
+    shutdown::handle_signals(cancellation.clone());
+    // the latest run for each schedule
+    let mut runs: HashMap<usize, JoinHandle<()>> = HashMap::new();
+
+    loop {
+        let now = Local::now();
+        let next = match schedules
+            .iter()
+            .filter_map(|(_, schedule)| schedule.after(&now).next())
+            .min()
+        {
+            Some(next) => next,
+            None => {
+                println!("None of the schedules will ever come up again, exiting");
+                return Ok(());
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep((next - Local::now()).to_std().unwrap_or_default()) => {}
+            _ = cancellation.cancelled() => {
+                for (_, run) in runs {
+                    let _ = run.await;
+                }
+                println!("Stopped gregory daemon");
+                return Ok(());
+            }
+        }
+
+        for (i, (package_name, schedule)) in schedules.iter().enumerate() {
+            if schedule.after(&now).next() != Some(next) {
+                continue;
+            }
+
+            let name = match package_name {
+                Some(package_name) => format!("packages.{package_name}"),
+                None => "everything".to_string(),
+            };
+            if runs.get(&i).is_some_and(|run| !run.is_finished()) {
+                println!("Skipping scheduled run for {name}, since the last one is still going");
+                continue;
+            }
+
+            println!("Starting scheduled run for {name}");
+            let options = RunOptions {
+                selection: JobSelection {
+                    packages: package_name.iter().cloned().collect(),
+                    ..Default::default()
+                },
+                job_locks: job_locks.clone(),
+                cancellation: cancellation.clone(),
+                trigger: Trigger::Schedule,
+                ..Default::default()
+            };
+            let config_path = config_path.clone();
+            runs.insert(
+                i,
+                tokio::spawn(async move {
+                    if let Err(e) = run(config_path, options).await {
+                        eprintln!("[ERROR] Scheduled run for {name} failed: {e}");
+                    }
+                }),
+            );
+        }
+    }
+}
+
+/// Parses a schedule in cron syntax
+///
+/// Both the standard 5 fields (`minute hour day-of-month month day-of-week`) and the [`cron`] crate's format, which adds seconds at the start and an optional year at the end, are accepted
+pub(crate) fn parse_schedule(schedule: &str) -> Result<Schedule, Error> {
+    let expression = if schedule.split_whitespace().count() == 5 {
+        format!("0 {schedule}")
+    } else {
+        schedule.to_string()
+    };
+
+    return Schedule::from_str(&expression)
+        .map_err(|e| Error::InvalidSchedule(schedule.to_string(), e));
+}
@@ -4,6 +4,7 @@ use crate::errors::Error;
 use serde::Deserialize;
 use std::time;
 use std::{collections::HashMap, fs, thread};
+use uuid::Uuid;
 
 /// The config for gregory
 #[derive(Debug, Clone, Deserialize)]
@@ -16,6 +17,18 @@ pub(crate) struct Config {
     pub(crate) max_threads: f32,
     #[serde(default = "data", rename = "data-dir")]
     pub(crate) data_dir: String,
+    /// Whether to write jobs' output to log files in `data-dir`, as well as to the database
+    ///
+    /// Default: true
+    #[serde(default = "log_files", rename = "log-files")]
+    pub(crate) log_files: bool,
+    /// When to run every job in daemon mode, in cron syntax
+    pub(crate) schedule: Option<String>,
+    /// How long jobs can run for, in seconds, unless a job sets its own `timeout`; no limit by default
+    pub(crate) timeout: Option<u64>,
+    /// What to run jobs with, unless a job sets its own - see [`Runtime`]
+    #[serde(default)]
+    pub(crate) runtime: Runtime,
     /// Holds the packages, including their compilation and packaging
     ///
     /// See config reference in the docs for details.
@@ -30,6 +43,12 @@ pub(crate) struct Config {
     /// Format: `librewolf = "./data/librewolf:/librewolf"` - like Docker/Podman formatting
     #[serde(default = "volumes")]
     pub(crate) volumes: HashMap<String, String>,
+    /// Hooks to run when stuff happens - see [`Hooks`]
+    #[serde(default)]
+    pub(crate) hooks: Hooks,
+    /// Where to store run metadata and jobs' output - see [`Database`]
+    #[serde(default)]
+    pub(crate) database: Database,
 }
 
 impl Config {
@@ -74,6 +93,131 @@ pub(crate) struct Job {
     pub(crate) privileged: bool,
     #[serde(default = "shell")]
     pub(crate) shell: String,
+    /// What to run this job with, overriding the `runtime` from [`Config`]
+    pub(crate) runtime: Option<Runtime>,
+    /// How long the job can run for, in seconds, before it's stopped, overriding the `timeout` from [`Config`]
+    pub(crate) timeout: Option<u64>,
+    /// How many more times to try running the job if it fails
+    ///
+    /// Default: 0
+    #[serde(default)]
+    pub(crate) retries: u32,
+    /// How long to wait before the first retry, in seconds; it doubles for each retry after that
+    ///
+    /// Default: 30
+    #[serde(default = "retry_delay", rename = "retry-delay")]
+    pub(crate) retry_delay: u64,
+}
+
+/// What to run jobs with - see [`crate::runtime`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Runtime {
+    /// Run jobs in containers with Podman
+    #[default]
+    Podman,
+    /// Run jobs in containers with Docker
+    Docker,
+    /// Run jobs directly on the host, with no container
+    Host,
+}
+
+impl Runtime {
+    /// Returns the name of the runtime, as it's written in the config
+    pub(crate) fn name(&self) -> &'static str {
+        return match self {
+            Runtime::Podman => "podman",
+            Runtime::Docker => "docker",
+            Runtime::Host => "host",
+        };
+    }
+}
+
+/// The database config - see [`crate::storage`]
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct Database {
+    /// Which database to use
+    #[serde(default)]
+    pub(crate) backend: DatabaseBackend,
+    /// Where the database file goes, for SQLite (`gregory.db` in `data-dir` by default), or the directory for the files for JSON lines (`no-db` in `data-dir` by default)
+    pub(crate) path: Option<String>,
+    /// The postgres connection URL, e.g. `postgres://gregory@localhost/gregory`; the other postgres settings override what's in it
+    pub(crate) url: Option<String>,
+    /// The postgres host, or the directory its unix socket is in if it starts with `/`
+    pub(crate) host: Option<String>,
+    pub(crate) port: Option<u16>,
+    pub(crate) user: Option<String>,
+    /// A file holding the postgres password, so it doesn't have to go in the config
+    #[serde(rename = "password-file")]
+    pub(crate) password_file: Option<String>,
+    /// The name of the postgres database; `gregory` by default
+    pub(crate) dbname: Option<String>,
+    /// The postgres SSL mode, e.g. `require` - see the [postgres docs](https://www.postgresql.org/docs/current/libpq-ssl.html#LIBPQ-SSL-SSLMODE-STATEMENTS)
+    pub(crate) sslmode: Option<String>,
+}
+
+/// Which database to use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DatabaseBackend {
+    /// PostgreSQL - see [`crate::storage::PostgresStorage`]
+    #[default]
+    Postgres,
+    /// An SQLite database file
+    Sqlite,
+    /// JSON lines files, for running without a database - see [`crate::storage::JsonLinesStorage`]
+    #[serde(rename = "json-lines")]
+    JsonLines,
+}
+
+impl Job {
+    /// Returns how long to wait before retrying after attempt number `attempt` (starting at 1) failed - `retry_delay`, doubled for every attempt before it
+    pub(crate) fn retry_backoff(&self, attempt: u32) -> time::Duration {
+        return time::Duration::from_secs(
+            self.retry_delay
+                .saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1))),
+        );
+    }
+
+    /// Returns how many threads the job gets, i.e. `threads`, limited to `max_threads`
+    pub(crate) fn capped_threads(&self, max_threads: f32) -> f32 {
+        if self.threads > max_threads {
+            return max_threads;
+        }
+        return self.threads;
+    }
+}
+
+/// A hook - either a command run on the host, or a [`Job`] run in a container like any other
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Hook {
+    /// A command to run on the host with `sh -c`
+    Command(String),
+    /// A job to run in a container
+    Job(Job),
+}
+
+/// The hooks for each event
+///
+/// Hooks for jobs get the job's id, revision, and UUID as environment variables (`GREGORY_JOB_ID`, `GREGORY_JOB_REVISION`, `GREGORY_JOB_UUID`), plus `GREGORY_EXIT_CODE` and `GREGORY_LOG_PATH` once the job has finished
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct Hooks {
+    /// Run before every job
+    #[serde(default, rename = "before-job")]
+    pub(crate) before_job: Vec<Hook>,
+    /// Run after every job, whether it succeeded or not
+    #[serde(default, rename = "after-job")]
+    pub(crate) after_job: Vec<Hook>,
+    /// Run after a job fails
+    #[serde(default, rename = "on-job-failure")]
+    pub(crate) on_job_failure: Vec<Hook>,
+    /// Run after an `update-repo` job succeeds
+    #[serde(default, rename = "after-update-repo")]
+    pub(crate) after_update_repo: Vec<Hook>,
+    /// Run once all the jobs in a run are done; these get `GREGORY_SUCCEEDED`, `GREGORY_FAILED`, `GREGORY_TIMED_OUT`, and `GREGORY_SKIPPED` instead, which are space-separated lists of job ids
+    #[serde(default, rename = "on-run-complete")]
+    pub(crate) on_run_complete: Vec<Hook>,
 }
 
 /// Holds the data for a certain package's config
@@ -82,6 +226,13 @@ pub(crate) struct Package {
     /// What other packages gregory handles which this depends on
     #[serde(default = "dependencies")]
     pub(crate) dependencies: Vec<String>,
+    /// Commands to check the version of the package - what they print to stdout is the version
+    ///
+    /// These are run in the same container setup as the compilation job (or the first packaging job if there's no compilation)
+    #[serde(rename = "version-check", alias = "version_check")]
+    pub(crate) version_check: Option<Vec<String>>,
+    /// When to run this package's jobs in daemon mode, in cron syntax
+    pub(crate) schedule: Option<String>,
     /// The compilation [`Job`] - optional
     pub(crate) compilation: Option<Job>,
     /// The packaging [`Job`]s, organized by the distro/repo name
@@ -102,16 +253,72 @@ pub(crate) struct JobExitStatus {
     ///
     /// Anyways I'll stop rambling now.
     pub(crate) exit_code: Option<i32>,
-    /// Where the log is
-    ///
-    /// TEMPORARY
-    pub(crate) log_path: String,
+    /// Where the log file is, if there is one
+    pub(crate) log_path: Option<String>,
     /// How long it took to run the job
     pub(crate) duration: time::Duration,
-    /// The name of the container this job ran in
-    pub(crate) container_name: String,
     /// Uuid
-    pub(crate) job_uuid: String,
+    pub(crate) job_uuid: Uuid,
+    /// Whether the job was stopped for running past its timeout
+    pub(crate) timed_out: bool,
+    /// Whether the job was stopped because the run was cancelled (e.g. with Ctrl-C)
+    pub(crate) cancelled: bool,
+}
+
+/// What happened to a [`Job`] in a run
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum JobStatus {
+    /// The job ran and exited with code 0
+    Succeeded,
+    /// The job ran and exited with a non-zero exit code, or was killed by a signal
+    Failed,
+    /// The job ran past its timeout, so it was stopped
+    TimedOut,
+    /// The job wasn't run, for the reason given
+    Skipped(String),
+    /// The job was stopped because the run was cancelled
+    Cancelled,
+    /// The job hadn't started yet when the run was cancelled, for the reason given
+    NotRun(String),
+}
+
+impl JobStatus {
+    /// Returns the status for a job that ran
+    pub(crate) fn from_exit_status(job_exit_status: &JobExitStatus) -> JobStatus {
+        if job_exit_status.cancelled {
+            return JobStatus::Cancelled;
+        } else if job_exit_status.timed_out {
+            return JobStatus::TimedOut;
+        } else if job_exit_status.exit_code == Some(0) {
+            return JobStatus::Succeeded;
+        }
+        return JobStatus::Failed;
+    }
+
+    /// Returns whether the job failed, i.e. whether things that depend on it shouldn't run
+    pub(crate) fn is_failure(&self) -> bool {
+        return matches!(self, JobStatus::Failed | JobStatus::TimedOut);
+    }
+
+    /// Returns the name of the status, as stored in the database
+    pub(crate) fn name(&self) -> &'static str {
+        return match self {
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::TimedOut => "timed-out",
+            JobStatus::Skipped(_) => "skipped",
+            JobStatus::Cancelled => "cancelled",
+            JobStatus::NotRun(_) => "not-run",
+        };
+    }
+
+    /// Returns why the job wasn't run, if it wasn't
+    pub(crate) fn reason(&self) -> Option<&str> {
+        return match self {
+            JobStatus::Skipped(reason) | JobStatus::NotRun(reason) => Some(reason),
+            _ => None,
+        };
+    }
 }
 
 // ==========================
@@ -159,11 +366,21 @@ pub(crate) fn shell() -> String {
     return "/bin/sh".to_string();
 }
 
+/// Default (30 seconds) for how long to wait before retrying a job
+pub(crate) fn retry_delay() -> u64 {
+    return 30;
+}
+
 /// Default revision (`1`)
 pub(crate) fn revision() -> String {
     return "1".to_string();
 }
 
+/// Default (true) for whether to write log files
+pub(crate) fn log_files() -> bool {
+    return true;
+}
+
 pub(crate) fn data() -> String {
     return "./data".to_string();
 }
@@ -1,9 +1,39 @@
 use thiserror::Error;
+use uuid::Uuid;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("I/O error: {0}")]
     IOError(#[from] std::io::Error),
     #[error("error while deserializing TOML: {0}")]
     DeserError(#[from] toml::de::Error),
+    #[error("dependency cycle detected: {}", .0.join(" -> "))]
+    DependencyCycle(Vec<String>),
+    #[error("found {0} error(s) in the config")]
+    InvalidConfig(usize),
+    #[error("unknown job id: {0}")]
+    UnknownJob(String),
+    #[error("no runs of {0} found in the database")]
+    NoJobRuns(String),
+    #[error("unknown run: {0}")]
+    UnknownRun(Uuid),
+    #[error("unknown package: {0}")]
+    UnknownPackage(String),
+    #[error("unknown distro: {0}; no packages are packaged for it, and there's no `update-repo` job for it")]
+    UnknownDistro(String),
+    #[error("invalid schedule `{0}`: {1}")]
+    InvalidSchedule(String, cron::error::Error),
+    #[error("no schedules set in the config; add `schedule` at the top level or to a package")]
+    NoSchedules,
+    #[error("database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("invalid database config: {0}")]
+    DatabaseConfig(String),
+    #[error("job output stopped being written to the database, due to an earlier error")]
+    OutputWriterStopped,
+    #[error("the database is at schema version {0}, but this version of gregory only knows up to version {1}; it needs to be updated")]
+    SchemaTooNew(i32, i32),
 }
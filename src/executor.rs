@@ -0,0 +1,720 @@
+//! Runs jobs concurrently, within the limits set by `max-jobs` and `max-threads`
+
+use crate::cli::JobSelection;
+use crate::data::{Config, Job, JobExitStatus, JobStatus};
+use crate::errors::Error;
+use crate::hooks;
+use crate::logging;
+use crate::runtime;
+use crate::scheduler::JobGraph;
+use crate::shutdown::Cancellation;
+use crate::storage::{JobStart, OutputWriter, Storage};
+use crate::{job_id_to_metadata, run_job};
+use alphanumeric_sort::compare_str;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, sleep_until, Instant};
+use uuid::Uuid;
+
+/// The ids of jobs that are currently running, so that the same job is never run twice at once - shared between runs in daemon mode
+pub(crate) type JobLocks = Arc<Mutex<HashSet<String>>>;
+
+/// Options for how jobs are run
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RunOptions {
+    /// Run every job, even if it's up-to-date
+    pub(crate) force: bool,
+    /// Job ids to run even if they're up-to-date
+    pub(crate) force_jobs: Vec<String>,
+    /// Which jobs to run (see [`State::select`](crate::State::select))
+    pub(crate) selection: JobSelection,
+    /// The run to carry on with, rather than starting a new one; the jobs that already succeeded in it aren't run again
+    pub(crate) resume: Option<Uuid>,
+    /// What started the run
+    pub(crate) trigger: Trigger,
+    /// Don't use a database; write everything to JSON lines files instead (see [`JsonLinesStorage`](crate::storage::JsonLinesStorage))
+    pub(crate) no_db: bool,
+    /// The jobs running in other runs, which will be waited on rather than run at the same time
+    pub(crate) job_locks: JobLocks,
+    /// Stops the run when it's cancelled, e.g. by Ctrl-C (see [`shutdown::handle_signals`](crate::shutdown::handle_signals))
+    pub(crate) cancellation: Cancellation,
+}
+
+/// What started a run
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum Trigger {
+    /// `gregory run`
+    #[default]
+    Manual,
+    /// A schedule, in daemon mode
+    Schedule,
+}
+
+impl Trigger {
+    /// Returns the name of the trigger, as stored in the database
+    pub(crate) fn name(&self) -> &'static str {
+        return match self {
+            Trigger::Manual => "manual",
+            Trigger::Schedule => "schedule",
+        };
+    }
+}
+
+/// Why jobs are marked as not run once the run's cancelled
+const CANCELLED_REASON: &str = "the run was cancelled";
+
+/// A finished attempt at running a job
+struct Attempt {
+    job_id: String,
+    /// Which attempt this was, starting at 1
+    number: u32,
+    /// The run id of the first attempt, which all the attempts at a job share
+    group: Uuid,
+    start_time: SystemTime,
+    /// The digest of the image the job ran in
+    digest: Option<String>,
+    /// `None` if it wasn't started, since the run was cancelled first
+    job_exit_status: Option<JobExitStatus>,
+}
+
+/// A job that failed, waiting for its backoff (see [`Job::retry_backoff`]) to be over before it's retried
+struct Retry {
+    job_id: String,
+    job: Job,
+    /// Which attempt is next
+    number: u32,
+    /// See [`Attempt::group`]
+    group: Uuid,
+    digest: Option<String>,
+    /// When the backoff's over
+    at: Instant,
+}
+
+/// Runs all the jobs in `graph`, with each job only being started once everything it depends on has finished
+///
+/// Up to `max-jobs` jobs are run at once, and `max-threads` is treated as a budget shared between them - a job is only started if its `threads` (see [`Job::capped_threads`]) fits in what's left
+///
+/// Package jobs are skipped if they're up-to-date (see [`up_to_date_reason`]), unless they're forced to run by `options`.
+///
+/// Failed jobs are retried up to their `retries`, waiting longer before each retry (see [`Job::retry_backoff`]) - while they wait, other jobs can have their place and threads. If a job still fails after that, everything that depends on it is skipped, except for `update-repo` jobs - those wait for all their packaging jobs to finish, and are only skipped if none of them succeeded (see [`update_repo_skip_reason`]).
+///
+/// When resuming a run (see [`RunOptions::resume`]), the jobs that already succeeded in it (or in the runs it resumed, and so on) count as succeeded without being run again, so everything downstream of them is run as usual.
+///
+/// If the run's cancelled, the running jobs are stopped, and nothing else is started or retried - those jobs are marked as not run. Returns what happened to each job.
+pub(crate) async fn run_jobs(
+    conf: &Config,
+    graph: &JobGraph,
+    jobs: &HashMap<String, Job>,
+    options: &RunOptions,
+    run_uuid: &Uuid,
+    storage: &dyn Storage,
+    output: &OutputWriter,
+) -> Result<HashMap<String, JobStatus>, Error> {
+    let max_jobs = conf.max_jobs.max(1) as usize;
+
+    // jobs that haven't been started, in the order they should be started in
+    let mut pending: Vec<String> = graph.order().unwrap();
+    let mut statuses: HashMap<String, JobStatus> = HashMap::new();
+    // maps package names to their versions, as their version checks finish
+    let mut versions: HashMap<String, String> = HashMap::new();
+    // maps the running jobs to how many threads they're using
+    let mut running: HashMap<String, f32> = HashMap::new();
+    let mut tasks: JoinSet<Attempt> = JoinSet::new();
+    // failed jobs waiting to be retried
+    let mut retries: Vec<Retry> = Vec::new();
+    let mut hook_tasks: JoinSet<()> = JoinSet::new();
+
+    // when resuming, whatever already succeeded in the resumed runs (at the same revision) is done
+    let mut resumed = options.resume;
+    while let Some(resumed_uuid) = resumed {
+        for record in storage.jobs_in_run(&resumed_uuid).await? {
+            let job_id = record.job_id;
+            if record.status.as_deref() != Some("succeeded")
+                || !pending.contains(&job_id)
+                || record.revision != jobs[&job_id].revision
+            {
+                continue;
+            }
+            println!("Not running {job_id}: it already succeeded in run {resumed_uuid}");
+            pending.retain(|pending_id| *pending_id != job_id);
+            let (_, package_name, name) = job_id_to_metadata(job_id.clone());
+            if let (true, Some(version)) = (name == "version-check", record.package_version) {
+                versions.insert(package_name, version);
+            }
+            statuses.insert(job_id, JobStatus::Succeeded);
+        }
+        resumed = match storage.find_run(&resumed_uuid).await? {
+            Some(run) => run.resumed_from,
+            None => None,
+        };
+    }
+
+    loop {
+        if options.cancellation.is_cancelled() {
+            let waiting = retries.drain(..).map(|retry| retry.job_id);
+            for job_id in waiting.chain(pending.drain(..)).collect::<Vec<String>>() {
+                println!("Not running {job_id}: {CANCELLED_REASON}");
+                let status = JobStatus::NotRun(CANCELLED_REASON.to_string());
+                storage
+                    .log_unstarted_job(run_uuid, &job_id, &jobs[&job_id].revision, &status)
+                    .await?;
+                statuses.insert(job_id, status);
+            }
+        }
+
+        // retry anything whose backoff is over, if it fits, before starting anything new
+        let mut i = 0;
+        while i < retries.len() && running.len() < max_jobs {
+            let threads = retries[i].job.capped_threads(conf.max_threads);
+            if retries[i].at > Instant::now()
+                || !fits(&running, threads, conf.max_threads)
+                || !options
+                    .job_locks
+                    .lock()
+                    .unwrap()
+                    .insert(retries[i].job_id.clone())
+            {
+                i += 1;
+                continue;
+            }
+
+            let retry = retries.remove(i);
+            running.insert(retry.job_id.clone(), threads);
+            println!(
+                "Running {} ({threads} threads, attempt {} of {})",
+                retry.job_id,
+                retry.number,
+                retry.job.retries + 1
+            );
+            spawn_attempt(
+                &mut tasks,
+                conf,
+                output,
+                &options.cancellation,
+                *run_uuid,
+                retry.job_id,
+                retry.job,
+                retry.number,
+                retry.group,
+                retry.digest,
+            );
+        }
+
+        // start everything that's ready and fits
+        let mut finished: HashSet<String> = statuses.keys().cloned().collect();
+        let mut i = 0;
+        while i < pending.len() && running.len() < max_jobs {
+            let job_id = pending[i].clone();
+            let job = jobs[&job_id].clone();
+            let threads = job.capped_threads(conf.max_threads);
+
+            if !graph.dependencies(&job_id).is_subset(&finished)
+                || !fits(&running, threads, conf.max_threads)
+                || !options.job_locks.lock().unwrap().insert(job_id.clone())
+            {
+                i += 1;
+                continue;
+            }
+
+            pending.remove(i);
+            let digest = {
+                let conf = conf.clone();
+                let job = job.clone();
+                tokio::task::spawn_blocking(move || {
+                    runtime::for_job(&conf, &job).image_digest(&job.image)
+                })
+                .await
+                .unwrap()
+            };
+            let forced = options.force || options.force_jobs.contains(&job_id);
+
+            let skip_reason = match update_repo_skip_reason(&job_id, graph, &statuses) {
+                Some(reason) => Some(reason),
+                None if forced => None,
+                None => {
+                    up_to_date_reason(&job_id, &job, &digest, graph, &versions, storage).await?
+                }
+            };
+            if let Some(reason) = skip_reason {
+                options.job_locks.lock().unwrap().remove(&job_id);
+                println!("Skipping {job_id}: {reason}");
+                let status = JobStatus::Skipped(reason);
+                storage
+                    .log_unstarted_job(run_uuid, &job_id, &job.revision, &status)
+                    .await?;
+                // so what depends on it (which is always later in `pending`) can start straight away
+                finished.insert(job_id.clone());
+                statuses.insert(job_id, status);
+                continue;
+            }
+
+            running.insert(job_id.clone(), threads);
+            println!("Running {job_id} ({threads} threads)");
+            spawn_attempt(
+                &mut tasks,
+                conf,
+                output,
+                &options.cancellation,
+                *run_uuid,
+                job_id,
+                job,
+                1,
+                Uuid::now_v7(),
+                digest,
+            );
+        }
+
+        // the next retry that's still waiting for its backoff; ones that are ready but don't fit wait like everything else
+        let next_retry = retries
+            .iter()
+            .map(|retry| retry.at)
+            .filter(|at| *at > Instant::now())
+            .min();
+        let Attempt {
+            job_id,
+            number: attempt,
+            group: attempt_group,
+            start_time,
+            digest,
+            job_exit_status,
+        } = tokio::select! {
+            Some(result) = tasks.join_next() => result.unwrap(),
+            // a retry's backoff is over
+            _ = sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => continue,
+            // so the jobs that haven't started get marked as not run straight away
+            _ = options.cancellation.cancelled(), if !pending.is_empty() || !retries.is_empty() => continue,
+            // everything left is waiting on jobs that are running in another run
+            _ = sleep(Duration::from_secs(5)), if tasks.is_empty() && (!pending.is_empty() || !retries.is_empty()) => continue,
+            else => break,
+        };
+        let job_exit_status = match job_exit_status {
+            Some(job_exit_status) => job_exit_status,
+            None => {
+                println!("Not running {job_id}: {CANCELLED_REASON}");
+                running.remove(&job_id);
+                options.job_locks.lock().unwrap().remove(&job_id);
+                let status = JobStatus::NotRun(CANCELLED_REASON.to_string());
+                storage
+                    .log_unstarted_job(run_uuid, &job_id, &jobs[&job_id].revision, &status)
+                    .await?;
+                statuses.insert(job_id, status);
+                continue;
+            }
+        };
+        // make sure all its output's in the database before it's used
+        output.flush().await?;
+
+        match job_exit_status.exit_code {
+            _ if job_exit_status.cancelled => {
+                println!("Finished {job_id}, cancelled");
+            }
+            _ if job_exit_status.timed_out => {
+                println!("Finished {job_id}, timed out");
+            }
+            Some(e) => {
+                println!("Finished {job_id}, exit code {e}");
+            }
+            None => {
+                println!("Finished {job_id}, !!! no exit code !!!");
+                println!(" This means the process was terminated by a signal, like SIGKILL, which you should probably look into. See also: https://doc.rust-lang.org/std/process/struct.ExitStatus.html#method.code")
+            }
+        }
+
+        let (_, package_name, name) = job_id_to_metadata(job_id.clone());
+        let mut status = JobStatus::from_exit_status(&job_exit_status);
+        if name == "version-check" && status == JobStatus::Succeeded {
+            match check_version(&package_name, &job_exit_status, storage).await? {
+                Some(version) => {
+                    versions.insert(package_name.clone(), version);
+                }
+                None => {
+                    status = JobStatus::Failed;
+                }
+            }
+        }
+
+        match &job_exit_status.log_path {
+            Some(log_path) => {
+                println!(" Logging metadata to the database; log file at {log_path}")
+            }
+            None => println!(" Logging metadata to the database"),
+        }
+        storage
+            .finish_job(
+                &job_exit_status.job_uuid,
+                start_time + job_exit_status.duration,
+                job_exit_status.exit_code,
+                &status,
+                package_version(&job_id, &versions).cloned(),
+            )
+            .await?;
+
+        let job = job_exit_status.job.clone();
+        // only the last attempt decides what happens to everything downstream of it
+        let retrying =
+            status.is_failure() && attempt <= job.retries && !options.cancellation.is_cancelled();
+
+        // run the hooks in the background, so they don't hold up other jobs
+        hook_tasks.spawn_blocking({
+            let conf = conf.clone();
+            let job_id = job_id.clone();
+            let status = status.clone();
+            move || {
+                let env = hooks::job_env(
+                    &job_id,
+                    &job_exit_status.job.revision,
+                    &job_exit_status.job_uuid,
+                    Some(&job_exit_status),
+                );
+                hooks::run_hooks(&conf, "after-job", &conf.hooks.after_job, &env);
+                if status.is_failure() {
+                    // it hasn't failed for good if it's being retried
+                    if !retrying {
+                        hooks::run_hooks(&conf, "on-job-failure", &conf.hooks.on_job_failure, &env);
+                    }
+                } else if job_id.starts_with("update-repo.") {
+                    hooks::run_hooks(
+                        &conf,
+                        "after-update-repo",
+                        &conf.hooks.after_update_repo,
+                        &env,
+                    );
+                }
+            }
+        });
+
+        // it gives up its place even if it's being retried, so it doesn't hold anything else up while it waits
+        running.remove(&job_id);
+        options.job_locks.lock().unwrap().remove(&job_id);
+        if retrying {
+            let backoff = job.retry_backoff(attempt);
+            println!(
+                "Retrying {job_id} in {} seconds (attempt {} of {})",
+                backoff.as_secs(),
+                attempt + 1,
+                job.retries + 1
+            );
+            retries.push(Retry {
+                job_id,
+                job,
+                number: attempt + 1,
+                group: attempt_group,
+                digest,
+                at: Instant::now() + backoff,
+            });
+            continue;
+        }
+
+        if status.is_failure() {
+            // skip everything downstream of it
+            let mut dependents: Vec<String> = graph
+                .dependents(&job_id)
+                .into_iter()
+                .filter(|dependent| {
+                    pending.contains(dependent) && !dependent.starts_with("update-repo.")
+                })
+                .collect();
+            dependents.sort();
+            for dependent in dependents {
+                let reason = format!("dependency {job_id} failed");
+                println!("Skipping {dependent}: {reason}");
+                pending.retain(|job_id| *job_id != dependent);
+                let status = JobStatus::Skipped(reason);
+                storage
+                    .log_unstarted_job(run_uuid, &dependent, &jobs[&dependent].revision, &status)
+                    .await?;
+                statuses.insert(dependent, status);
+            }
+        }
+        statuses.insert(job_id, status);
+    }
+
+    // wait for any hooks that are still running
+    while hook_tasks.join_next().await.is_some() {}
+
+    return Ok(statuses);
+}
+
+/// Starts attempt number `number` at running a job, in the background
+///
+/// The first attempt's run id is `group`, and the rest get their own. It isn't started if `cancellation` is cancelled before it gets going.
+#[allow(clippy::too_many_arguments)]
+fn spawn_attempt(
+    tasks: &mut JoinSet<Attempt>,
+    conf: &Config,
+    output: &OutputWriter,
+    cancellation: &Cancellation,
+    run_uuid: Uuid,
+    job_id: String,
+    job: Job,
+    number: u32,
+    group: Uuid,
+    digest: Option<String>,
+) {
+    let conf = conf.clone();
+    let output = output.clone();
+    let cancellation = cancellation.clone();
+    tasks.spawn(async move {
+        return tokio::task::spawn_blocking(move || {
+            if cancellation.is_cancelled() {
+                return Attempt {
+                    job_id,
+                    number,
+                    group,
+                    start_time: SystemTime::now(),
+                    digest,
+                    job_exit_status: None,
+                };
+            }
+
+            let run_id = if number == 1 { group } else { Uuid::now_v7() };
+            hooks::run_hooks(
+                &conf,
+                "before-job",
+                &conf.hooks.before_job,
+                &hooks::job_env(&job_id, &job.revision, &run_id, None),
+            );
+
+            let start_time = SystemTime::now();
+            output.job_started(JobStart {
+                uuid: run_id,
+                run_uuid,
+                start_time,
+                job_id: job_id.clone(),
+                revision: job.revision.clone(),
+                attempt_group: group,
+                attempt: number,
+                log_path: conf
+                    .log_files
+                    .then(|| logging::log_path(&conf.data_dir, &job_id, &job.revision, &run_id)),
+                image_digest: digest.clone(),
+            });
+            let job_exit_status = run_job(
+                &conf,
+                job_id.clone(),
+                job,
+                run_id,
+                &HashMap::new(),
+                Some(output),
+                &cancellation,
+            );
+            return Attempt {
+                job_id,
+                number,
+                group,
+                start_time,
+                digest,
+                job_exit_status: Some(job_exit_status),
+            };
+        })
+        .await
+        .unwrap();
+    });
+}
+
+/// Returns whether a job using `threads` can start alongside the ones `running`, without going over `max_threads` - anything can start if nothing else is running, so jobs that need the whole budget still run
+pub(crate) fn fits(running: &HashMap<String, f32>, threads: f32, max_threads: f32) -> bool {
+    return running.is_empty() || running.values().sum::<f32>() + threads <= max_threads;
+}
+
+/// Returns why an `update-repo` job should be skipped, if it should be - i.e. if none of the packaging jobs for its distro ran, or all the ones that did failed
+///
+/// Returns `None` for anything that isn't an `update-repo` job
+pub(crate) fn update_repo_skip_reason(
+    job_id: &str,
+    graph: &JobGraph,
+    statuses: &HashMap<String, JobStatus>,
+) -> Option<String> {
+    let distro = job_id.strip_prefix("update-repo.")?;
+
+    let packaging_statuses: Vec<&JobStatus> = graph
+        .dependencies(job_id)
+        .iter()
+        .filter(|dep| {
+            dep.starts_with("packages.") && dep.ends_with(&format!(".packaging.{distro}"))
+        })
+        .filter_map(|dep| statuses.get(dep))
+        .collect();
+
+    if packaging_statuses.contains(&&JobStatus::Succeeded) {
+        return None;
+    } else if packaging_statuses.iter().any(|status| status.is_failure()) {
+        return Some(format!("none of the packaging jobs for {distro} succeeded"));
+    } else {
+        return Some(format!("none of the packaging jobs for {distro} ran"));
+    }
+}
+
+/// Reads the version from a finished version check and records it, returning `None` (and logging an error) if it printed anything to stderr
+///
+/// Also warns if the version seems to have decreased since the last check
+async fn check_version(
+    package_name: &str,
+    job_exit_status: &JobExitStatus,
+    storage: &dyn Storage,
+) -> Result<Option<String>, Error> {
+    let stderr = job_output(job_exit_status, "stderr", storage).await?;
+    if !stderr.is_empty() {
+        eprintln!(
+            "[ERROR] Version check for {package_name} printed to stderr, so it'll be ignored:"
+        );
+        for line in stderr {
+            eprintln!(" {line}");
+        }
+        return Ok(None);
+    }
+
+    let version = job_output(job_exit_status, "stdout", storage)
+        .await?
+        .join("\n")
+        .trim()
+        .to_string();
+    if version.is_empty() {
+        eprintln!(
+            "[ERROR] Version check for {package_name} didn't print a version, so it'll be ignored"
+        );
+        return Ok(None);
+    }
+
+    if let Some(last_version) = storage.last_package_version(package_name).await? {
+        if compare_str(&version, &last_version) == Ordering::Less {
+            println!("[WARNING] Version of {package_name} seems to have decreased, from {last_version} to {version}");
+        }
+    }
+    storage
+        .log_package_version(package_name, &version, &job_exit_status.job_uuid)
+        .await?;
+    println!(" {package_name} is at version {version}");
+
+    return Ok(Some(version));
+}
+
+/// Returns the lines a finished job printed to `stream` (`stdout` or `stderr`), from its log file if it has one, otherwise from the database
+async fn job_output(
+    job_exit_status: &JobExitStatus,
+    stream: &str,
+    storage: &dyn Storage,
+) -> Result<Vec<String>, Error> {
+    return match &job_exit_status.log_path {
+        Some(log_path) => logging::read_stream(log_path, stream),
+        None => storage.job_output(&job_exit_status.job_uuid, stream).await,
+    };
+}
+
+/// Returns why a job should be skipped for being up-to-date, if it should be
+///
+/// A job is up-to-date if the last time it succeeded, it had the same revision, package version (see [`check_version`]), and image digest, and none of the jobs it depends on have succeeded since then - whether in this run, or in an earlier one where the job itself then failed
+///
+/// Returns `None` for version checks and `update-repo` jobs, which are always run
+async fn up_to_date_reason(
+    job_id: &str,
+    job: &Job,
+    digest: &Option<String>,
+    graph: &JobGraph,
+    versions: &HashMap<String, String>,
+    storage: &dyn Storage,
+) -> Result<Option<String>, Error> {
+    let (category, _, name) = job_id_to_metadata(job_id.to_string());
+    if category != "packages" || name == "version-check" {
+        return Ok(None);
+    }
+    // if the image can't be found, there's no way to tell if it changed
+    if digest.is_none() {
+        return Ok(None);
+    }
+
+    let last_run = match storage.last_successful_run(job_id).await? {
+        Some(last_run) => last_run,
+        None => return Ok(None),
+    };
+
+    // version checks are run every time, and the version they found is compared below
+    for dep in graph.dependencies(job_id) {
+        if dep.ends_with(".version-check") {
+            continue;
+        }
+        if let Some(dep_run) = storage.last_successful_run(&dep).await? {
+            if dep_run.start_time > last_run.start_time {
+                return Ok(None);
+            }
+        }
+    }
+    if last_run.revision == job.revision
+        && last_run.package_version.as_ref() == package_version(job_id, versions)
+        && last_run.image_digest == *digest
+    {
+        let revision = last_run.revision;
+        return Ok(Some(match last_run.package_version {
+            Some(version) => format!("revision {revision} was already built for version {version}"),
+            None => format!("revision {revision} was already built"),
+        }));
+    }
+    return Ok(None);
+}
+
+/// Returns the version found for a package job's package, if there is one
+///
+/// `update-repo` jobs never have one, even if there's a package with the same name as their distro
+fn package_version<'a>(job_id: &str, versions: &'a HashMap<String, String>) -> Option<&'a String> {
+    let (category, package_name, _) = job_id_to_metadata(job_id.to_string());
+    if category != "packages" {
+        return None;
+    }
+    return versions.get(&package_name);
+}
+
+/// Prints a summary of how all the jobs went
+pub(crate) fn print_summary(statuses: &HashMap<String, JobStatus>) {
+    let mut job_ids: Vec<&String> = statuses.keys().collect();
+    job_ids.sort();
+
+    let with_status = |name: &str| -> Vec<&String> {
+        return job_ids
+            .iter()
+            .filter(|job_id| statuses[**job_id].name() == name)
+            .copied()
+            .collect();
+    };
+    let succeeded = with_status("succeeded");
+    let failed = with_status("failed");
+    let timed_out = with_status("timed-out");
+    let skipped = with_status("skipped");
+    let cancelled = with_status("cancelled");
+    let not_run = with_status("not-run");
+
+    println!();
+    println!("Summary:");
+    println!(" Succeeded ({}):", succeeded.len());
+    for job_id in succeeded {
+        println!("  {job_id}");
+    }
+    println!(" Failed ({}):", failed.len());
+    for job_id in failed {
+        println!("  {job_id}");
+    }
+    println!(" Timed out ({}):", timed_out.len());
+    for job_id in timed_out {
+        println!("  {job_id}");
+    }
+    println!(" Skipped ({}):", skipped.len());
+    for job_id in skipped {
+        println!(
+            "  {job_id} - {}",
+            statuses[job_id].reason().unwrap_or_default()
+        );
+    }
+    // these only happen if the run was cancelled, so they're left out otherwise
+    if !cancelled.is_empty() || !not_run.is_empty() {
+        println!(" Cancelled ({}):", cancelled.len());
+        for job_id in cancelled {
+            println!("  {job_id}");
+        }
+        println!(" Not run ({}):", not_run.len());
+        for job_id in not_run {
+            println!("  {job_id}");
+        }
+    }
+}
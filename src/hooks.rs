@@ -0,0 +1,95 @@
+//! Hooks - commands or jobs run when stuff happens, like a job failing
+
+use crate::data::{Config, Hook, JobExitStatus, JobStatus};
+use crate::run_job;
+use crate::shutdown::Cancellation;
+use std::collections::HashMap;
+use std::process::Command;
+use uuid::Uuid;
+
+/// Runs the hooks for an event (e.g. `on-job-failure`), in order
+///
+/// Hooks failing doesn't affect anything else, it's just logged
+pub(crate) fn run_hooks(conf: &Config, event: &str, hooks: &[Hook], env: &HashMap<String, String>) {
+    for (i, hook) in hooks.iter().enumerate() {
+        let exit_code = match hook {
+            Hook::Command(command) => Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .envs(env)
+                .status()
+                .ok()
+                .and_then(|status| status.code()),
+            Hook::Job(job) => {
+                run_job(
+                    conf,
+                    format!("hooks.{event}.{i}"),
+                    job.clone(),
+                    Uuid::now_v7(),
+                    env,
+                    None,
+                    // hooks are left to finish, even if the run's cancelled
+                    &Cancellation::default(),
+                )
+                .exit_code
+            }
+        };
+
+        if exit_code != Some(0) {
+            eprintln!("[WARNING] Hook {event}.{i} failed, exit code {exit_code:?}");
+        }
+    }
+}
+
+/// Returns the environment variables passed to hooks for a job
+///
+/// `GREGORY_EXIT_CODE` and `GREGORY_LOG_PATH` are only set once the job has finished, i.e. if `job_exit_status` is passed; `GREGORY_EXIT_CODE` is empty if there wasn't an exit code, and `GREGORY_LOG_PATH` isn't set if there's no log file
+pub(crate) fn job_env(
+    job_id: &str,
+    revision: &str,
+    uuid: &Uuid,
+    job_exit_status: Option<&JobExitStatus>,
+) -> HashMap<String, String> {
+    let mut env = HashMap::from([
+        ("GREGORY_JOB_ID".to_string(), job_id.to_string()),
+        ("GREGORY_JOB_REVISION".to_string(), revision.to_string()),
+        ("GREGORY_JOB_UUID".to_string(), uuid.to_string()),
+    ]);
+
+    if let Some(job_exit_status) = job_exit_status {
+        env.insert(
+            "GREGORY_EXIT_CODE".to_string(),
+            job_exit_status
+                .exit_code
+                .map(|code| code.to_string())
+                .unwrap_or_default(),
+        );
+        if let Some(log_path) = &job_exit_status.log_path {
+            env.insert("GREGORY_LOG_PATH".to_string(), log_path.clone());
+        }
+    }
+
+    return env;
+}
+
+/// Returns the environment variables passed to `on-run-complete` hooks - space-separated lists of the job ids that succeeded, failed, timed out, and were skipped
+pub(crate) fn run_env(statuses: &HashMap<String, JobStatus>) -> HashMap<String, String> {
+    let mut job_ids: Vec<&String> = statuses.keys().collect();
+    job_ids.sort();
+
+    let with_status = |name: &str| -> String {
+        return job_ids
+            .iter()
+            .filter(|job_id| statuses[**job_id].name() == name)
+            .map(|job_id| job_id.as_str())
+            .collect::<Vec<&str>>()
+            .join(" ");
+    };
+
+    return HashMap::from([
+        ("GREGORY_SUCCEEDED".to_string(), with_status("succeeded")),
+        ("GREGORY_FAILED".to_string(), with_status("failed")),
+        ("GREGORY_TIMED_OUT".to_string(), with_status("timed-out")),
+        ("GREGORY_SKIPPED".to_string(), with_status("skipped")),
+    ]);
+}
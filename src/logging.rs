@@ -1,200 +1,133 @@
 use uuid::Uuid;
 
 use crate::errors::Error;
-use std::env;
-use std::fs::{create_dir_all, File, OpenOptions};
+use crate::storage::{OutputLine, OutputWriter};
+use chrono::Utc;
+use std::fs::{self, create_dir_all, File, OpenOptions};
 use std::io::Write;
 use std::path::Path;
-use std::time::{Instant, SystemTime};
+use std::time::Instant;
 
-/// Logging for a [`Job`]
-// TODO: log to postgres instead; maybe i already made a comment todo-ing this idk
+/// Logging for a [`Job`], to a log file and/or the database (see [`OutputWriter`])
 pub(crate) struct JobLogger {
-    log_file: File,
-    path: String,
+    log_file: Option<File>,
+    path: Option<String>,
+    run_id: Uuid,
+    /// Where to send output to be written to the database
+    output: Option<OutputWriter>,
+    /// How many lines have been logged so far, across both stdout and stderr
+    line_count: i32,
 }
 
 impl JobLogger {
+    /// Creates a logger for a run of a job
+    ///
+    /// The log file is only written if `log_file` is true, or if there's no `output` (since otherwise the output would just be lost)
     pub(crate) fn new(
         data_dir: String,
         job_id: String,
         revision: String,
         run_id: Uuid,
+        log_file: bool,
+        output: Option<OutputWriter>,
     ) -> JobLogger {
+        let mut logger = JobLogger {
+            log_file: None,
+            path: None,
+            run_id,
+            output,
+            line_count: 0,
+        };
+        if !log_file && logger.output.is_some() {
+            return logger;
+        }
+
         // get path and create the dir.
-        let log_path = format!("{data_dir}/logs/{job_id}/{revision}/{run_id}");
+        let log_path = log_path(&data_dir, &job_id, &revision, &run_id);
         let log_dir = Path::new(&log_path).parent().unwrap();
         create_dir_all(log_dir).unwrap();
 
-        return JobLogger {
-            log_file: OpenOptions::new()
+        logger.log_file = Some(
+            OpenOptions::new()
                 .create_new(true)
                 .append(true)
                 .open(&log_path)
                 .unwrap(),
-            path: log_path,
-        };
+        );
+        logger.path = Some(log_path);
+        return logger;
     }
 
     /// Log something printed to stdout
     ///
     /// Fun gregory lore: I originally typo'd this as "Strign" and the linter didn't catch it for some reason
     pub(crate) fn stdout(&mut self, text: String, start_time: Instant) -> Result<(), Error> {
-        match writeln!(
-            &mut self.log_file,
-            "[{:.3}] [stdout] {}",
-            start_time.elapsed().as_millis() as f64 / 1000.0,
-            text
-        ) {
-            Ok(_) => return Ok(()),
-            Err(e) => {
-                return Err(Error::IOError(e));
-            }
+        self.send_output("stdout", &text);
+        if let Some(log_file) = &mut self.log_file {
+            writeln!(
+                log_file,
+                "[{:.3}] [stdout] {}",
+                start_time.elapsed().as_millis() as f64 / 1000.0,
+                text
+            )?;
         }
+        return Ok(());
     }
 
     /// Log something printed to stderr
     pub(crate) fn stderr(&mut self, text: String, start_time: Instant) -> Result<(), Error> {
-        match writeln!(
-            &mut self.log_file,
-            "[{}] [stderr] {}",
-            start_time.elapsed().as_millis() / 1000,
-            text
-        ) {
-            Ok(_) => return Ok(()),
-            Err(e) => {
-                return Err(Error::IOError(e));
-            }
+        self.send_output("stderr", &text);
+        if let Some(log_file) = &mut self.log_file {
+            writeln!(
+                log_file,
+                "[{}] [stderr] {}",
+                start_time.elapsed().as_millis() / 1000,
+                text
+            )?;
         }
+        return Ok(());
     }
 
-    /// Returns the path the job's output was logged to
-    pub(crate) fn path(&self) -> String {
-        return self.path.clone();
-    }
-}
-
-pub(crate) mod sql {
-    use chrono::{DateTime, Utc};
-    use sqlx::{Connection, PgConnection};
-    use std::{env, time::SystemTime};
-
-    /// Returns a new connection to postgres
-    ///
-    /// *x*: How many times to retry the reconnect
-    pub(crate) async fn start(x: u16) -> PgConnection {
-        let mut conn = db_connect_with_retries(x).await;
-        create_tables(&mut conn).await;
-        return conn;
-    }
-
-    /// Returns the database environment variables
-    ///
-    /// Format: (address, username, password)
-    pub(crate) fn db_vars() -> (String, String, String) {
-        let db_address: String = match env::var("GREGORY_DB_ADDRESS") {
-            Ok(address) => address,
-            Err(_) => {
-                panic!("Environment variable `GREGORY_DB_ADDRESS` not set")
-            }
-        };
-        let db_user: String = match env::var("GREGORY_DB_USER") {
-            Ok(user) => user,
-            Err(_) => {
-                panic!("Environment variable `GREGORY_DB_USER` not set")
-            }
-        };
-        let db_pass: String = match env::var("GREGORY_DB_PASSWORD") {
-            Ok(pass) => pass,
-            Err(_) => {
-                panic!("Environment variable `GREGORY_DB_PASSWORD` not set")
-            }
-        };
-
-        return (db_address, db_user, db_pass);
-    }
-
-    /// Returns the connection to the database
-    pub(crate) async fn db_connection() -> Result<PgConnection, sqlx::Error> {
-        let (db_address, db_user, db_pass) = db_vars();
-        let uri = format!("postgres://{db_user}:{db_pass}@{db_address}/gregory");
-        return PgConnection::connect(uri.as_str()).await;
-    }
-
-    pub(crate) async fn db_connect_with_retries(x: u16) -> PgConnection {
-        let mut conn = db_connection().await;
-        if conn.is_ok() {
-            return conn.unwrap();
-        }
-
-        for _ in 0..x {
-            conn = db_connection().await;
-            if conn.is_ok() {
-                break;
-            }
+    /// Sends a line to be written to the database, if there's somewhere to send it
+    fn send_output(&mut self, stream: &'static str, text: &str) {
+        self.line_count += 1;
+        if let Some(output) = &self.output {
+            output.line(OutputLine {
+                uuid: self.run_id,
+                line_number: self.line_count,
+                stream: stream.to_string(),
+                time: Utc::now(),
+                text: text.to_string(),
+            });
         }
-
-        return conn.unwrap();
     }
 
-    // TODO: when adding logging to postgres directly, update this so it 1) adds the job at the start, 2) logs line-by-line, and 3) adds the end time and exit code at the end of the job
-    pub(crate) async fn log_job(
-        conn: &mut PgConnection,
-        start_time: SystemTime,
-        end_time: SystemTime,
-        exit_code: Option<i32>,
-        job_id: String,
-        revision: String,
-        uuid: String,
-        log_path: String,
-    ) {
-        let start_time: DateTime<Utc> = start_time.into();
-        let start_time = start_time.format("%+").to_string();
-        let end_time: DateTime<Utc> = end_time.into();
-        let end_time = end_time.format("%+").to_string();
-        let exit_code = match exit_code {
-            Some(code) => code.to_string(),
-            None => "NULL".to_string(),
-        };
-        let query = format!("INSERT INTO job_logs (start_time, end_time, exit_code, job_id, revision, uuid, log_path) VALUES ('{start_time}', '{end_time}', {exit_code}, '{job_id}', '{revision}', '{uuid}', '{log_path}')");
-        sqlx::query(query.as_str())
-            .execute(conn.as_mut())
-            .await
-            .unwrap();
+    /// Returns the path the job's output was logged to, if it was logged to a file
+    pub(crate) fn path(&self) -> Option<String> {
+        return self.path.clone();
     }
+}
 
-    /// Tries to connect to the database *x* times, panics after reaching that limit
+/// Returns where the log file for a run of a job goes
+pub(crate) fn log_path(data_dir: &str, job_id: &str, revision: &str, run_id: &Uuid) -> String {
+    return format!("{data_dir}/logs/{job_id}/{revision}/{run_id}");
+}
 
-    /// Creates table(s) for gregory if they don't exist already
-    pub(crate) async fn create_tables(conn: &mut PgConnection) {
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS job_logs (
-    start_time   timestamp,
-    end_time    timestamp,
-    duration    interval GENERATED ALWAYS AS (end_time - start_time) STORED,
-    exit_code    smallint,
-    job_id     text,
-    revision    text,
-    uuid      text,
-    container_name  text GENERATED ALWAYS AS (job_id || '-' || uuid) STORED,
-    log_path        text
-);
-",
-        )
-        .execute(conn)
-        .await
-        .unwrap();
-    }
+/// Splits a log file line (`[<timestamp>] [<stream>] <text>`) into its stream and text
+///
+/// Only the marker straight after the timestamp counts, so text that happens to look like a marker is left alone
+pub(crate) fn parse_log_line(line: &str) -> Option<(&str, &str)> {
+    let (_, rest) = line.strip_prefix('[')?.split_once("] ")?;
+    return rest.strip_prefix('[')?.split_once("] ");
 }
 
-#[test]
-pub(crate) fn test_db_vars() {
-    assert_eq!(
-        (
-            "postgres".to_string(),
-            "gregory".to_string(),
-            "pass".to_string()
-        ),
-        sql::db_vars()
-    )
+/// Returns the lines from a job's log file that were printed to `stream` (`stdout` or `stderr`), without the timestamps and stream markers
+pub(crate) fn read_stream(log_path: &str, stream: &str) -> Result<Vec<String>, Error> {
+    let lines = fs::read_to_string(log_path)?
+        .lines()
+        .filter_map(parse_log_line)
+        .filter(|(line_stream, _)| *line_stream == stream)
+        .map(|(_, text)| text.to_string())
+        .collect();
+    return Ok(lines);
 }
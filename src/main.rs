@@ -1,30 +1,48 @@
 use crate::cli::*;
 use crate::data::*;
-use better_commands;
+use crate::errors::Error;
+use crate::executor::RunOptions;
+use crate::runtime::JobSpec;
+use crate::scheduler::JobGraph;
+use crate::shutdown::Cancellation;
+use crate::storage::{JsonLinesStorage, OutputWriter, RunRecord};
 use clap::{CommandFactory, Parser};
 use clap_complete::aot::{generate, Bash, Elvish, Fish, PowerShell, Zsh};
-use logging::sql;
-use sqlx::PgConnection;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fs::create_dir_all;
-use std::fs::remove_dir_all;
+use std::fs::read;
+use std::fs::remove_file;
 use std::fs::write;
 use std::fs::File;
-use std::io::stdout;
+use std::io::{self, stdout};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
-use std::process::Command;
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::Instant;
-use std::time::SystemTime;
+use std::thread;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 mod cli;
+mod daemon;
 mod data;
 mod errors;
+mod executor;
+mod hooks;
 mod logging;
+mod migrations;
+mod plan;
+mod queries;
+mod runtime;
+mod scheduler;
+mod shutdown;
+mod storage;
 mod tests;
+mod validate;
 
 #[tokio::main]
 async fn main() {
@@ -48,122 +66,309 @@ async fn main() {
                 generate(PowerShell, &mut Cli::command(), binary_name, &mut stdout());
             }
         },
-        Commands::Run { config } => {
-            run(config).await;
+        Commands::Run {
+            config,
+            force,
+            force_jobs,
+            no_db,
+            resume,
+            selection,
+        } => {
+            let options = RunOptions {
+                force,
+                force_jobs,
+                no_db,
+                resume,
+                selection,
+                ..Default::default()
+            };
+            shutdown::handle_signals(options.cancellation.clone());
+            if let Err(e) = run(config, options.clone()).await {
+                eprintln!("[ERROR] {e}");
+                exit(1);
+            }
+            if options.cancellation.is_cancelled() {
+                exit(130);
+            }
+        }
+        Commands::Daemon { config } => {
+            if let Err(e) = daemon::daemon(config).await {
+                eprintln!("[ERROR] {e}");
+                exit(1);
+            }
+        }
+        Commands::Validate { config } => {
+            if let Err(e) = validate_config(&config) {
+                eprintln!("[ERROR] {e}");
+                exit(1);
+            }
+        }
+        Commands::Plan {
+            config,
+            format,
+            selection,
+        } => {
+            if let Err(e) = plan::plan(config, format, &selection).await {
+                eprintln!("[ERROR] {e}");
+                exit(1);
+            }
+        }
+        Commands::Status { config, format } => {
+            if let Err(e) = queries::status(config, format).await {
+                eprintln!("[ERROR] {e}");
+                exit(1);
+            }
+        }
+        Commands::History {
+            job_id,
+            config,
+            limit,
+            format,
+        } => {
+            if let Err(e) = queries::history(config, job_id, limit, format).await {
+                eprintln!("[ERROR] {e}");
+                exit(1);
+            }
+        }
+        Commands::Logs {
+            job_id,
+            config,
+            run,
+            revision,
+            follow,
+            stream,
+        } => {
+            if let Err(e) = queries::logs(config, job_id, run, revision, follow, stream).await {
+                eprintln!("[ERROR] {e}");
+                exit(1);
+            }
+        }
+        Commands::Runs {
+            config,
+            limit,
+            format,
+        } => {
+            if let Err(e) = queries::runs(config, limit, format).await {
+                eprintln!("[ERROR] {e}");
+                exit(1);
+            }
+        }
+        Commands::Db { config, command } => {
+            if let Err(e) = db(config, command).await {
+                eprintln!("[ERROR] {e}");
+                exit(1);
+            }
         }
     }
 }
 
-async fn run(config_path: String) {
-    let config = Config::from_file(config_path).unwrap(); // this reads the file to a [`Config`] thing
-    let state = State::from_config(config.clone()).await;
-
-    // TODO: improve efficiency of all this logic
-    // TODO: Also clean it up and split it into different functions, especially the job sorter
-    // TODO: figure all this out and stuff and update the comments above this - the dependency map is done though
-
-    let mut ordered: Vec<String> = Vec::new(); // holds the job ids in order of how they should be run
-
-    let mut update_repo_jobs: HashMap<String, Job> = HashMap::new();
-    for (repo, job) in config.clone().update_repo {
-        update_repo_jobs.insert(format!("update-repo.{}", repo), job);
-    }
-
-    // TODO: Add logic to add repo update repos when relevant (see dependencies) here - or maybe do that logic earlier?
-
-    let failed_packages: Vec<String> = Vec::new();
-
-    let mut pg_connection = sql::start(5).await;
-
-    // runs the jobs (will need to be updated after sorting is added)
-    for (job_id, job) in state.jobs {
-        println!("Running {job_id}");
-        let start_time = SystemTime::now();
-        let job_exit_status = run_job(&state.conf, job_id.clone(), job.clone());
-        match job_exit_status.exit_code.clone() {
-            Some(e) => {
-                println!(" Job completed, exit code {e}");
+/// Runs a `db` subcommand
+async fn db(config_path: String, command: DbCommands) -> Result<(), Error> {
+    let conf = Config::from_file(config_path)?;
+    let storage = storage::connect(&conf).await?;
+
+    match command {
+        DbCommands::Migrate => {
+            let applied = storage.migrate().await?;
+            for migration in &applied {
+                println!(
+                    "Applied migration {} ({})",
+                    migration.version, migration.description
+                );
             }
-            None => {
-                println!(" Job completed, !!! no exit code !!!");
-                println!("  This means the process was terminated by a signal, like SIGKILL, which you should probably look into. See also: https://doc.rust-lang.org/std/process/struct.ExitStatus.html#method.code")
+            if applied.is_empty() {
+                println!("Database is already up-to-date");
+            }
+        }
+        DbCommands::Import { from } => {
+            let from = from.unwrap_or(storage::json_lines_dir(&conf));
+            if !Path::new(&from).is_dir() {
+                return Err(Error::IOError(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{from} doesn't exist"),
+                )));
+            }
+            if conf.database.backend == DatabaseBackend::JsonLines {
+                return Err(Error::DatabaseConfig(
+                    "can't import into JSON lines files; set `backend` to a database".to_string(),
+                ));
+            }
+            for migration in storage.migrate().await? {
+                println!(
+                    "Applied migration {} ({})",
+                    migration.version, migration.description
+                );
+            }
+            let counts = storage::import(&JsonLinesStorage::open(&from)?, storage.as_ref()).await?;
+            println!(
+                "Imported {} runs, {} jobs ({} were already there), {} lines of output, and {} package versions from {from}",
+                counts.runs,
+                counts.jobs, counts.existing_jobs, counts.output_lines, counts.package_versions
+            );
+        }
+        DbCommands::Status => {
+            let applied = storage.applied_migrations().await?;
+            println!(
+                "Schema version: {} (latest is {})",
+                storage.schema_version().await?,
+                migrations::latest_version(storage.migrations())
+            );
+            for migration in storage.migrations() {
+                match applied.get(&migration.version) {
+                    Some(applied_at) => println!(
+                        " {} - {} - applied {}",
+                        migration.version, migration.description, applied_at
+                    ),
+                    None => println!(
+                        " {} - {} - pending",
+                        migration.version, migration.description
+                    ),
+                }
             }
         }
+    }
 
-        sql::log_job(
-            &mut pg_connection,
-            start_time,
-            start_time + job_exit_status.duration,
-            job_exit_status.exit_code,
-            job_id,
-            job.revision,
-            job_exit_status.job_uuid,
-            job_exit_status.log_path.clone(),
-        )
-        .await;
+    return Ok(());
+}
 
-        println!(
-            " Logged metadata to postgres database; log file at {}",
-            job_exit_status.log_path
-        );
+/// Checks the config for problems (see [`validate`]), and says so if there aren't any
+fn validate_config(config_path: &str) -> Result<(), Error> {
+    let warnings = validate::check(config_path)?.len();
+    if warnings == 0 {
+        println!("No problems found in {config_path}");
+    } else {
+        println!("No errors found in {config_path}, but {warnings} warning(s)");
+    }
+    return Ok(());
+}
 
-        println!()
+/// Returns the SHA-256 hash of the config file, in hex
+fn config_hash(config_path: &str) -> Result<String, Error> {
+    let hash = Sha256::digest(read(config_path)?);
+    return Ok(hash.iter().map(|byte| format!("{byte:02x}")).collect());
+}
+
+/// Runs the jobs from the config, returning what happened to each of them
+pub(crate) async fn run(
+    config_path: String,
+    options: RunOptions,
+) -> Result<HashMap<String, JobStatus>, Error> {
+    validate::check(&config_path)?;
+    let config_hash = config_hash(&config_path)?;
+    let mut state = State::from_file(config_path).await?;
+    if options.no_db {
+        // only keeping `path` if it's already for JSON lines, rather than e.g. the SQLite database
+        state.conf.database = Database {
+            backend: DatabaseBackend::JsonLines,
+            path: Some(storage::json_lines_dir(&state.conf)),
+            ..Default::default()
+        };
     }
+    let graph = state.select(&JobGraph::new(&state.dependency_map)?, &options.selection)?;
 
-    // run repo updates
-    for (job_id, job) in update_repo_jobs {
-        println!("Running {job_id}");
-        let start_time = SystemTime::now();
-        let job_exit_status = run_job(&state.conf, job_id.clone(), job.clone());
-        match job_exit_status.exit_code.clone() {
-            Some(e) => {
-                println!(" Job completed, exit code {e}");
-            }
-            None => {
-                println!(" Job completed, !!! no exit code !!!");
-                println!("  This means the process was terminated by a signal, like SIGKILL, which you should probably look into. See also: https://doc.rust-lang.org/std/process/struct.ExitStatus.html#method.code")
-            }
+    for job_id in &options.force_jobs {
+        if !state.jobs.contains_key(job_id) {
+            return Err(Error::UnknownJob(job_id.clone()));
         }
+    }
 
-        sql::log_job(
-            &mut pg_connection,
-            start_time,
-            start_time + job_exit_status.duration,
-            job_exit_status.exit_code,
-            job_id,
-            job.revision,
-            job_exit_status.job_uuid,
-            job_exit_status.log_path.clone(),
-        )
-        .await;
-
+    let storage = storage::start(&state.conf).await?;
+    if options.no_db {
         println!(
-            " Logged metadata to postgres database; log file at {}",
-            job_exit_status.log_path
+            "Running without a database; run metadata will be written to {}",
+            storage::json_lines_dir(&state.conf)
         );
-
-        println!()
     }
-}
-
-fn run_job(conf: &Config, job_id: String, job: Job) -> JobExitStatus {
-    // limit threads to max_threads in the config
-    let mut threads = job.threads;
-    if job.threads > conf.max_threads {
-        threads = conf.max_threads;
+    // a resumed run gets its own row, so its duration doesn't include however long it was stopped for
+    if let Some(uuid) = options.resume {
+        let resumed = match storage.find_run(&uuid).await? {
+            Some(run) => run,
+            None => return Err(Error::UnknownRun(uuid)),
+        };
+        if resumed.config_hash != config_hash {
+            println!("[WARNING] The config has changed since run {uuid} started");
+        }
     }
+    let mut run = RunRecord::start(config_hash, options.trigger, options.resume);
+    storage.run_started(&run).await?;
+    options.cancellation.run_started(storage.clone(), &run);
+    match options.resume {
+        Some(uuid) => println!("Resuming run {uuid} as run {}", run.uuid),
+        None => println!("Starting run {}", run.uuid),
+    }
+    let (output, output_task) = OutputWriter::start(storage.clone());
+
+    let statuses = executor::run_jobs(
+        &state.conf,
+        &graph,
+        &state.jobs,
+        &options,
+        &run.uuid,
+        storage.as_ref(),
+        &output,
+    )
+    .await;
+    drop(output);
+    let statuses = match statuses {
+        // the output writer's error says what actually went wrong
+        Err(Error::OutputWriterStopped) => {
+            output_task.await.unwrap()?;
+            return Err(Error::OutputWriterStopped);
+        }
+        statuses => statuses?,
+    };
+    output_task.await.unwrap()?;
+    run.finish(&statuses);
+    storage.finish_run(&run).await?;
+    options.cancellation.run_finished(&run.uuid);
+
+    executor::print_summary(&statuses);
+
+    let env = hooks::run_env(&statuses);
+    tokio::task::spawn_blocking(move || {
+        hooks::run_hooks(
+            &state.conf,
+            "on-run-complete",
+            &state.conf.hooks.on_run_complete,
+            &env,
+        );
+    })
+    .await
+    .unwrap();
 
-    let run_id = Uuid::now_v7();
+    return Ok(statuses);
+}
 
+/// How often a running job checks whether it should be stopped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Runs a job with its runtime (see [`runtime::for_job`]), with `env` set as environment variables in it
+///
+/// Its output is sent to `output` to be written to the database, if it's passed (see [`logging::JobLogger::new`])
+///
+/// It's stopped if it runs past its timeout, or if `cancellation` is cancelled
+fn run_job(
+    conf: &Config,
+    job_id: String,
+    job: Job,
+    run_id: Uuid,
+    env: &HashMap<String, String>,
+    output: Option<OutputWriter>,
+    cancellation: &Cancellation,
+) -> JobExitStatus {
     let job_logger = Arc::new(Mutex::new(logging::JobLogger::new(
         conf.data_dir.clone(),
         job_id.clone(),
         job.revision.clone(),
         run_id,
+        conf.log_files,
+        output,
     )));
 
+    let spec = job_spec(conf, &job_id, &job, &run_id.to_string(), env);
+
     // write the script
-    let script_path: String = format!("{}/tmp/{}.sh", conf.data_dir, run_id);
+    let script_path = spec.script_path.clone();
     let script_dir = Path::new(&script_path).parent().unwrap(); // create dir for the script
     create_dir_all(script_dir).unwrap();
     write(&script_path, job.commands.join("\n")).unwrap();
@@ -177,119 +382,179 @@ fn run_job(conf: &Config, job_id: String, job: Job) -> JobExitStatus {
     PermissionsExt::set_mode(&mut perms, 0o755);
 
     // run the job
-    let mut cmd_args: Vec<String> = vec![
-        "run".to_string(),
-        "--rm".to_string(),
-        format!("--name={job_id}-{run_id}"),
-        format!("--cpus={threads}"),
-        format!("--privileged={}", job.privileged),
-        format!("-v={script_path}:/gregory-entrypoint.sh"),
-    ];
-    for vol in job.clone().volumes.unwrap_or(Vec::new()) {
-        match conf.volumes.get(&vol) {
-            Some(item) => {
-                cmd_args.push(format!("-v={}", item));
-            }
-            None => {}
-        }
-    }
-    cmd_args.push(format!(
-        "--entrypoint=[\"{}\", \"/gregory-entrypoint.sh\"]",
-        &job.shell
-    ));
-    cmd_args.push(job.clone().image);
-
-    let cmd_output = better_commands::run_funcs(
-        Command::new("podman").args(cmd_args),
-        {
-            let start_time = Instant::now();
-            let logger_clone = Arc::clone(&job_logger);
-            move |stdout_lines| {
-                for line in stdout_lines {
-                    let _ = logger_clone
-                        .lock()
-                        .unwrap()
-                        .stdout(line.unwrap(), start_time);
-                }
+    let runtime = runtime::for_job(conf, &job);
+    let mut cmd = runtime.command(&spec);
+
+    // stops the job if it runs past its timeout, or the run's cancelled
+    let deadline = job
+        .timeout
+        .or(conf.timeout)
+        .map(|timeout| Instant::now() + Duration::from_secs(timeout));
+    let timed_out = AtomicBool::new(false);
+    let cancelled = AtomicBool::new(false);
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    cancellation.started(&spec.name, &script_path);
+
+    let cmd_output = thread::scope(|scope| {
+        let (spec, runtime, timed_out, cancelled) = (&spec, &runtime, &timed_out, &cancelled);
+        scope.spawn(move || loop {
+            if done_rx.recv_timeout(STOP_CHECK_INTERVAL) != Err(RecvTimeoutError::Timeout) {
+                return;
             }
-        },
-        {
-            let start_time = Instant::now();
-            let logger_clone = Arc::clone(&job_logger);
-            move |stderr_lines| {
-                for line in stderr_lines {
-                    let _ = logger_clone
-                        .lock()
-                        .unwrap()
-                        .stderr(line.unwrap(), start_time);
+            if !timed_out.load(Ordering::SeqCst) && !cancelled.load(Ordering::SeqCst) {
+                if cancellation.is_cancelled() {
+                    cancelled.store(true, Ordering::SeqCst);
+                } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    timed_out.store(true, Ordering::SeqCst);
+                } else {
+                    continue;
                 }
             }
-        },
-    );
+            // keeps trying until it's done, in case it hadn't properly started yet
+            runtime.stop(spec);
+        });
+
+        let cmd_output = better_commands::run_funcs(
+            &mut cmd,
+            {
+                let start_time = Instant::now();
+                let logger_clone = Arc::clone(&job_logger);
+                move |stdout_lines| {
+                    for line in stdout_lines {
+                        let _ = logger_clone
+                            .lock()
+                            .unwrap()
+                            .stdout(line.unwrap(), start_time);
+                    }
+                }
+            },
+            {
+                let start_time = Instant::now();
+                let logger_clone = Arc::clone(&job_logger);
+                move |stderr_lines| {
+                    for line in stderr_lines {
+                        let _ = logger_clone
+                            .lock()
+                            .unwrap()
+                            .stderr(line.unwrap(), start_time);
+                    }
+                }
+            },
+        );
+        let _ = done_tx.send(());
+        return cmd_output;
+    });
 
-    // remove tmp dir/clean up
-    remove_dir_all(script_dir).unwrap();
+    // remove the script/clean up - just the script, since other jobs might be using the tmp dir
+    remove_file(&script_path).unwrap();
+    let _ = remove_file(runtime::host_pid_path(&spec));
+    cancellation.finished(&spec.name);
 
     let log_path = job_logger.lock().unwrap().path();
 
     return JobExitStatus {
-        container_name: script_path,
         duration: cmd_output.clone().duration(),
         job,
         exit_code: cmd_output.status_code(),
         log_path,
-        job_uuid: run_id.to_string(),
+        job_uuid: run_id,
+        timed_out: timed_out.into_inner(),
+        cancelled: cancelled.into_inner(),
+    };
+}
+
+/// Returns everything needed to run a job - see [`JobSpec`]
+///
+/// `run_id` is the uuid of this run of the job, or a placeholder for `plan`
+fn job_spec<'a>(
+    conf: &Config,
+    job_id: &str,
+    job: &'a Job,
+    run_id: &str,
+    env: &'a HashMap<String, String>,
+) -> JobSpec<'a> {
+    return JobSpec {
+        name: format!("{job_id}-{run_id}"),
+        job,
+        script_path: format!("{}/tmp/{}.sh", conf.data_dir, run_id),
+        // limit threads to max_threads in the config
+        threads: job.capped_threads(conf.max_threads),
+        volumes: job
+            .volumes
+            .iter()
+            .flatten()
+            .filter_map(|vol| conf.volumes.get(vol).cloned())
+            .collect(),
+        env,
     };
 }
 
-/// Turns a job name into the relevant data - (category  (i.e. "packaging"), package name (i.e. "librewolf"), name (i.e. "compilation"))
+/// Turns a job name into the relevant data - (category  (i.e. "packages"), package name (i.e. "librewolf"), name (i.e. "compilation" or "packaging.fedora"))
+///
+/// For `update-repo` jobs, the package name is the distro and the name is empty - (`update-repo`, `fedora`, ``)
 fn job_id_to_metadata(job_id: String) -> (String, String, String) {
     let data = job_id
-        .split(".")
+        .splitn(3, ".")
         .map(|item| item.to_string())
         .collect::<Vec<String>>();
-    return (data[0].clone(), data[1].clone(), data[2].clone());
+    return (
+        data[0].clone(),
+        data[1].clone(),
+        data.get(2).cloned().unwrap_or_default(),
+    );
 }
 
 /// Returns all the dependencies for a package recursively, *not* including the package's own jobs (e.g. compilation)
-fn recursive_deps_for_package(package_name: String, conf: Config) -> Vec<String> {
-    let mut deps: Vec<String> = Vec::new();
-
-    for dep_name in conf
+///
+/// `path` holds the packages currently being resolved, and is used to catch dependency cycles rather than recursing forever
+fn recursive_deps_for_package(
+    package_name: String,
+    conf: &Config,
+    path: &mut Vec<String>,
+) -> Result<Vec<String>, Error> {
+    if path.contains(&package_name) {
+        let mut cycle: Vec<String> = path[path.iter().position(|p| *p == package_name).unwrap()..]
+            .iter()
+            .map(|p| format!("packages.{p}"))
+            .collect();
+        cycle.push(format!("packages.{package_name}"));
+        return Err(Error::DependencyCycle(cycle));
+    }
+    path.push(package_name.clone());
+    let package = conf
         .packages
         .get(&package_name)
-        .unwrap()
-        .dependencies
-        .clone()
-    {
+        .ok_or(Error::UnknownPackage(package_name.clone()))?;
+
+    let mut deps: Vec<String> = Vec::new();
+
+    for dep_name in package.dependencies.clone() {
         // add recursive dependencies
         deps.append(&mut recursive_deps_for_package(
             dep_name.clone(),
-            conf.clone(),
-        ));
+            conf,
+            path,
+        )?);
+    }
+    // add its version check to deps
+    if State::version_check_job(package).is_some() {
+        deps.push(format!("packages.{package_name}.version-check"));
     }
+
     // add its compilation to deps
-    match conf
-        .packages
-        .get(&package_name)
-        .unwrap()
-        .compilation
-        .clone()
-    {
-        Some(_) => {
-            deps.push(format!("packages.{package_name}.compilation"));
-        }
-        None => {}
+    if package.compilation.is_some() {
+        deps.push(format!("packages.{package_name}.compilation"));
     }
 
     // add packaging jobs to deps
-    for (packaging_job_name, _) in conf.packages.get(&package_name).unwrap().packaging.clone() {
+    for packaging_job_name in package.packaging.keys() {
         deps.push(format!(
             "packages.{package_name}.packaging.{packaging_job_name}"
         ))
     }
 
-    return deps;
+    path.pop();
+    return Ok(deps);
 }
 
 struct State {
@@ -297,44 +562,31 @@ struct State {
     conf: Config,
     /// A hashmap mapping all job ids to what jobs depend on them (recursively)
     ///
-    /// Using the example config (`gregory.example.toml`):
-    ///
-    /// ```json
-    /// {
-    ///     "packages.some-librewolf-dependency.packaging.fedora": [
-    ///         "packages.librewolf.compilation",
-    ///         "packages.librewolf.packaging.fedora",
-    ///     ],
-    ///     "packages.some-librewolf-dependency.compilation": [
-    ///         "packages.librewolf.compilation",
-    ///         "packages.librewolf.packaging.fedora",
-    ///         "packages.some-librewolf-dependency.packaging.fedora",
-    ///     ],
-    ///     "packages.librewolf.compilation": [
-    ///         "packages.librewolf.packaging.fedora",
-    ///     ],
-    /// }
-    /// ```
+    /// See [`State::dependency_map()`] for an example
     dependency_map: HashMap<String, Vec<String>>,
     /// A hashmap mapping all job ids to their jobs
     jobs: HashMap<String, Job>,
 }
 
 impl State {
-    pub(crate) async fn from_file(filename: String) -> State {
-        let conf = Config::from_file(filename).unwrap();
+    pub(crate) async fn from_file(filename: String) -> Result<State, Error> {
+        let conf = Config::from_file(filename)?;
         return State::from_config(conf).await;
     }
 
-    pub(crate) async fn from_config(conf: Config) -> State {
+    pub(crate) async fn from_config(conf: Config) -> Result<State, Error> {
         let mut jobs = HashMap::new();
 
         for (package_name, package) in conf.clone().packages {
-            match package.compilation {
-                Some(tmp) => {
-                    jobs.insert(format!("packages.{}.compilation", package_name), tmp);
-                }
-                None => {}
+            if let Some(version_check) = State::version_check_job(&package) {
+                jobs.insert(
+                    format!("packages.{}.version-check", package_name),
+                    version_check,
+                );
+            }
+
+            if let Some(tmp) = package.compilation {
+                jobs.insert(format!("packages.{}.compilation", package_name), tmp);
             }
 
             for (job_name, job) in package.packaging {
@@ -345,11 +597,133 @@ impl State {
             }
         }
 
-        return State {
+        for (repo, job) in conf.clone().update_repo {
+            jobs.insert(format!("update-repo.{}", repo), job);
+        }
+
+        return Ok(State {
             conf: conf.clone(),
             jobs: jobs.clone(),
-            dependency_map: State::dependency_map(jobs, conf),
+            dependency_map: State::dependency_map(jobs, conf)?,
+        });
+    }
+
+    /// Returns the ids of all of a package's jobs, along with the `update-repo` jobs for the distros it's packaged for
+    pub(crate) fn package_jobs(&self, package_name: &str) -> Result<HashSet<String>, Error> {
+        let package = self
+            .conf
+            .packages
+            .get(package_name)
+            .ok_or(Error::UnknownPackage(package_name.to_string()))?;
+
+        let mut job_ids: HashSet<String> = self
+            .jobs
+            .keys()
+            .filter(|job_id| job_id.starts_with(&format!("packages.{package_name}.")))
+            .cloned()
+            .collect();
+        for distro in package.packaging.keys() {
+            if self.conf.update_repo.contains_key(distro) {
+                job_ids.insert(format!("update-repo.{distro}"));
+            }
+        }
+
+        return Ok(job_ids);
+    }
+
+    /// Returns the ids of all the packaging jobs for a distro, along with its `update-repo` job
+    pub(crate) fn distro_jobs(&self, distro: &str) -> Result<HashSet<String>, Error> {
+        let mut job_ids: HashSet<String> = self
+            .jobs
+            .keys()
+            .filter(|job_id| {
+                job_id.starts_with("packages.") && job_id.ends_with(&format!(".packaging.{distro}"))
+            })
+            .cloned()
+            .collect();
+        if self.conf.update_repo.contains_key(distro) {
+            job_ids.insert(format!("update-repo.{distro}"));
+        }
+
+        if job_ids.is_empty() {
+            return Err(Error::UnknownDistro(distro.to_string()));
+        }
+        return Ok(job_ids);
+    }
+
+    /// Returns the part of `graph` with just the jobs picked by `selection`, or all of it if nothing's selected
+    ///
+    /// Whatever's selected, a package's version check is included along with any of its other jobs, since it's needed to tell whether they're up-to-date; and the `update-repo` job for any packaging job is included too.
+    pub(crate) fn select(
+        &self,
+        graph: &JobGraph,
+        selection: &JobSelection,
+    ) -> Result<JobGraph, Error> {
+        if selection.packages.is_empty()
+            && selection.distros.is_empty()
+            && selection.jobs.is_empty()
+        {
+            return Ok(graph.clone());
+        }
+
+        let mut selected: HashSet<String> = HashSet::new();
+        for package_name in &selection.packages {
+            selected.extend(self.package_jobs(package_name)?);
+        }
+        for distro in &selection.distros {
+            selected.extend(self.distro_jobs(distro)?);
+        }
+        for job_id in &selection.jobs {
+            if !self.jobs.contains_key(job_id) {
+                return Err(Error::UnknownJob(job_id.clone()));
+            }
+            selected.insert(job_id.clone());
+        }
+
+        for job_id in selected.clone() {
+            if selection.with_deps {
+                selected.extend(graph.dependencies(&job_id));
+            }
+            if selection.with_dependents {
+                selected.extend(graph.dependents(&job_id));
+            }
+        }
+
+        for job_id in selected.clone() {
+            let (category, package_name, name) = job_id_to_metadata(job_id);
+            if category != "packages" {
+                continue;
+            }
+            let version_check = format!("packages.{package_name}.version-check");
+            if self.jobs.contains_key(&version_check) {
+                selected.insert(version_check);
+            }
+            if let Some(distro) = name.strip_prefix("packaging.") {
+                if self.conf.update_repo.contains_key(distro) {
+                    selected.insert(format!("update-repo.{distro}"));
+                }
+            }
+        }
+
+        return Ok(graph.subgraph(&selected));
+    }
+
+    /// Returns the [`Job`] for a package's version check, if it has one
+    ///
+    /// It's the compilation job (or the first packaging job, alphabetically, if there's no compilation) with its commands swapped out for the version check, so it runs in the same image with the same volumes
+    fn version_check_job(package: &Package) -> Option<Job> {
+        let commands = package.version_check.clone()?;
+        let mut job = match package.compilation.clone() {
+            Some(compilation) => compilation,
+            None => {
+                let mut packaging: Vec<(&String, &Job)> = package.packaging.iter().collect();
+                packaging.sort_by(|a, b| a.0.cmp(b.0));
+                packaging.first()?.1.clone()
+            }
         };
+        job.commands = commands;
+        job.threads = job.threads.min(1.0);
+        return Some(job);
     }
 
     /// Returns a hashmap mapping all job ids to what jobs depend on them (recursively)
@@ -358,22 +732,40 @@ impl State {
     ///
     /// ```json
     /// {
+    ///     "packages.librewolf.version-check": [
+    ///         "packages.librewolf.compilation",
+    ///         "packages.librewolf.packaging.fedora",
+    ///         "update-repo.fedora",
+    ///     ],
     ///     "packages.some-librewolf-dependency.compilation": [
     ///         "packages.librewolf.compilation",
     ///         "packages.librewolf.packaging.fedora",
     ///         "packages.some-librewolf-dependency.packaging.fedora",
+    ///         "update-repo.fedora",
+    ///     ],
+    ///     "packages.librewolf.packaging.fedora": [
+    ///         "update-repo.fedora",
     ///     ],
-    ///     "packages.librewolf.packaging.fedora": [],
     ///     "packages.librewolf.compilation": [
     ///         "packages.librewolf.packaging.fedora",
+    ///         "update-repo.fedora",
     ///     ],
     ///     "packages.some-librewolf-dependency.packaging.fedora": [
     ///         "packages.librewolf.compilation",
     ///         "packages.librewolf.packaging.fedora",
+    ///         "update-repo.fedora",
     ///     ],
+    ///     "update-repo.fedora": [],
     /// }
     /// ```
-    fn dependency_map(jobs: HashMap<String, Job>, conf: Config) -> HashMap<String, Vec<String>> {
+    ///
+    /// `update-repo.{distro}` depends on every `packages.*.packaging.{distro}` job (and so everything they depend on)
+    ///
+    /// Errors if the packages' `dependencies` form a cycle
+    fn dependency_map(
+        jobs: HashMap<String, Job>,
+        conf: Config,
+    ) -> Result<HashMap<String, Vec<String>>, Error> {
         let mut dep_map: HashMap<String, Vec<String>> = HashMap::new(); // holds job ids and every job they depend on (recursively) - not just specified dependencies, also packaging depending on compilation
 
         for (job_id, _) in jobs.clone() {
@@ -381,7 +773,11 @@ impl State {
         }
 
         for (job_id, _) in jobs.clone() {
-            let (_, package_name, _) = job_id_to_metadata(job_id.clone());
+            let (category, package_name, name) = job_id_to_metadata(job_id.clone());
+            // version checks don't need to wait for anything
+            if category != "packages" || name == "version-check" {
+                continue;
+            }
 
             for dep_name in conf
                 .packages
@@ -390,34 +786,73 @@ impl State {
                 .dependencies
                 .clone()
             {
-                let all_deps = recursive_deps_for_package(dep_name.clone(), conf.clone());
+                let all_deps = recursive_deps_for_package(
+                    dep_name.clone(),
+                    &conf,
+                    &mut vec![package_name.clone()],
+                )?;
                 for dep in all_deps {
                     dep_map.get_mut(&dep).unwrap().push(job_id.clone());
                 }
             }
         }
 
+        // add version checks before everything else for the package
+        for (package_name, package) in conf.packages.clone() {
+            if let Some(version_check_deps) =
+                dep_map.get_mut(&format!("packages.{package_name}.version-check"))
+            {
+                if package.compilation.is_some() {
+                    version_check_deps.push(format!("packages.{package_name}.compilation"));
+                }
+                for job_name in package.packaging.keys() {
+                    version_check_deps
+                        .push(format!("packages.{package_name}.packaging.{job_name}"));
+                }
+            }
+        }
+
         // add compilation jobs when relevant
         for (package_name, package) in conf.packages {
             if package.compilation.is_some() {
-                if !dep_map.contains_key(&format!("packages.{package_name}.compilation")) {
-                    dep_map.insert(format!("packages.{package_name}.compilation"), Vec::new());
+                let compilation_deps = dep_map
+                    .entry(format!("packages.{package_name}.compilation"))
+                    .or_default();
+
+                for job_name in package.packaging.keys() {
+                    compilation_deps.push(format!("packages.{package_name}.packaging.{job_name}"));
                 }
+            }
+        }
 
-                for (job_name, _) in package.packaging {
-                    dep_map
-                        .get_mut(&format!("packages.{package_name}.compilation"))
-                        .unwrap()
-                        .push(format!("packages.{package_name}.packaging.{job_name}"));
+        // add update-repo jobs after the packaging jobs for their distro, and everything those depend on
+        let update_repo_for = |job_id: &String| -> Option<String> {
+            let (category, _, name) = job_id_to_metadata(job_id.clone());
+            let update_repo_id = format!("update-repo.{}", name.strip_prefix("packaging.")?);
+            if category == "packages" && jobs.contains_key(&update_repo_id) {
+                return Some(update_repo_id);
+            }
+            return None;
+        };
+        for (job_id, deps) in dep_map.iter_mut() {
+            let mut update_repo_deps: Vec<String> = Vec::new();
+            if let Some(update_repo_id) = update_repo_for(job_id) {
+                update_repo_deps.push(update_repo_id);
+            }
+            for dep in deps.iter() {
+                if let Some(update_repo_id) = update_repo_for(dep) {
+                    update_repo_deps.push(update_repo_id);
                 }
             }
+            deps.append(&mut update_repo_deps);
         }
 
         // deduplicate dependencies
         for (_, deps) in dep_map.iter_mut() {
+            deps.sort();
             deps.dedup();
         }
 
-        return dep_map;
+        return Ok(dep_map);
     }
 }
@@ -0,0 +1,204 @@
+//! Database migrations - how the schema gets from whatever version it's at to the latest one
+//!
+//! Migrations are only ever added to the end of the list, never changed or removed, since databases out there have already had them applied. Versions of gregory from before migrations existed created `job_logs` themselves, so the first one only creates it if it doesn't exist yet.
+
+/// A change to the database schema
+pub(crate) struct Migration {
+    /// Counts up from 1, in the order they're applied
+    pub(crate) version: i32,
+    pub(crate) description: &'static str,
+    /// The SQL for the change; can be multiple statements
+    pub(crate) sql: &'static str,
+}
+
+/// The migrations for postgres
+pub(crate) const POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create job_logs",
+        sql: "
+CREATE TABLE IF NOT EXISTS job_logs (
+    start_time   timestamp,
+    end_time    timestamp,
+    duration    interval GENERATED ALWAYS AS (end_time - start_time) STORED,
+    exit_code    smallint,
+    job_id     text,
+    revision    text,
+    uuid      text,
+    container_name  text GENERATED ALWAYS AS (job_id || '-' || uuid) STORED,
+    log_path        text
+);
+",
+    },
+    Migration {
+        version: 2,
+        description: "extend job_logs, and create job_output and package_versions",
+        // job_logs stored times as timestamps in UTC, and uuids as text
+        sql: "
+-- the generated columns depend on the ones being converted, so they have to be dropped and added back
+ALTER TABLE job_logs DROP COLUMN duration, DROP COLUMN container_name;
+
+ALTER TABLE job_logs
+    ALTER COLUMN start_time TYPE timestamptz USING start_time AT TIME ZONE 'UTC',
+    ALTER COLUMN end_time TYPE timestamptz USING end_time AT TIME ZONE 'UTC',
+    ALTER COLUMN uuid TYPE uuid USING uuid::uuid;
+
+ALTER TABLE job_logs
+    ADD COLUMN duration interval GENERATED ALWAYS AS (end_time - start_time) STORED,
+    ADD COLUMN container_name text GENERATED ALWAYS AS (job_id || '-' || uuid::text) STORED,
+    ADD COLUMN status text,
+    ADD COLUMN reason text,
+    ADD COLUMN package_version text,
+    ADD COLUMN image_digest text,
+    ADD COLUMN attempt_group uuid,
+    ADD COLUMN attempt smallint;
+
+CREATE TABLE job_output (
+    uuid        uuid,
+    line_number integer,
+    stream      text,
+    time        timestamptz,
+    text        text
+);
+
+CREATE INDEX job_output_uuid ON job_output (uuid);
+
+CREATE TABLE package_versions (
+    package     text,
+    version     text,
+    checked_at  timestamptz,
+    uuid        uuid
+);
+",
+    },
+    Migration {
+        version: 3,
+        description: "create runs",
+        sql: "
+CREATE TABLE IF NOT EXISTS runs (
+    uuid        uuid PRIMARY KEY,
+    start_time  timestamptz,
+    end_time    timestamptz,
+    duration    interval GENERATED ALWAYS AS (end_time - start_time) STORED,
+    config_hash text,
+    trigger     text,
+    status      text,
+    succeeded   integer,
+    failed      integer,
+    timed_out   integer,
+    skipped     integer
+);
+
+ALTER TABLE job_logs ADD COLUMN IF NOT EXISTS run_uuid uuid REFERENCES runs (uuid);
+
+CREATE INDEX IF NOT EXISTS job_logs_run_uuid ON job_logs (run_uuid);
+",
+    },
+    Migration {
+        version: 4,
+        description: "add cancelled and not_run counts to runs",
+        sql: "
+ALTER TABLE runs
+    ADD COLUMN IF NOT EXISTS cancelled integer,
+    ADD COLUMN IF NOT EXISTS not_run   integer;
+",
+    },
+    Migration {
+        version: 5,
+        description: "add resumed_from to runs",
+        sql: "
+ALTER TABLE runs ADD COLUMN IF NOT EXISTS resumed_from uuid REFERENCES runs (uuid);
+",
+    },
+];
+
+/// The migrations for SQLite
+///
+/// SQLite support was added after everything else, so it starts with the whole schema
+pub(crate) const SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create tables",
+        sql: "
+CREATE TABLE job_logs (
+    start_time   text,
+    end_time    text,
+    duration    real GENERATED ALWAYS AS ((julianday(end_time) - julianday(start_time)) * 86400) STORED,
+    exit_code    integer,
+    job_id     text,
+    revision    text,
+    uuid      text,
+    container_name  text GENERATED ALWAYS AS (job_id || '-' || uuid) STORED,
+    log_path        text,
+    status      text,
+    reason      text,
+    package_version text,
+    image_digest    text,
+    attempt_group   text,
+    attempt     integer
+);
+
+CREATE INDEX job_logs_uuid ON job_logs (uuid);
+
+CREATE TABLE job_output (
+    uuid        text,
+    line_number integer,
+    stream      text,
+    time        text,
+    text        text
+);
+
+CREATE INDEX job_output_uuid ON job_output (uuid);
+
+CREATE TABLE package_versions (
+    package     text,
+    version     text,
+    checked_at  text,
+    uuid        text
+);
+",
+    },
+    Migration {
+        version: 2,
+        description: "create runs",
+        sql: "
+CREATE TABLE runs (
+    uuid        text PRIMARY KEY,
+    start_time  text,
+    end_time    text,
+    duration    real GENERATED ALWAYS AS ((julianday(end_time) - julianday(start_time)) * 86400) STORED,
+    config_hash text,
+    trigger     text,
+    status      text,
+    succeeded   integer,
+    failed      integer,
+    timed_out   integer,
+    skipped     integer
+);
+
+ALTER TABLE job_logs ADD COLUMN run_uuid text REFERENCES runs (uuid);
+
+CREATE INDEX job_logs_run_uuid ON job_logs (run_uuid);
+",
+    },
+    Migration {
+        version: 3,
+        description: "add cancelled and not_run counts to runs",
+        sql: "
+ALTER TABLE runs ADD COLUMN cancelled integer;
+ALTER TABLE runs ADD COLUMN not_run integer;
+",
+    },
+    Migration {
+        version: 4,
+        description: "add resumed_from to runs",
+        sql: "
+ALTER TABLE runs ADD COLUMN resumed_from text REFERENCES runs (uuid);
+",
+    },
+];
+
+/// Returns the latest schema version
+pub(crate) fn latest_version(migrations: &[Migration]) -> i32 {
+    return migrations.last().map_or(0, |migration| migration.version);
+}
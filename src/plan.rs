@@ -0,0 +1,141 @@
+//! `gregory plan` - shows what a run would do, without running anything
+
+use crate::cli::{JobSelection, PlanFormat};
+use crate::errors::Error;
+use crate::scheduler::JobGraph;
+use crate::{job_spec, runtime, validate, State};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// What's put in place of the run id in container names and script paths, since it's different every run
+const RUN_ID_PLACEHOLDER: &str = "{run-id}";
+
+/// A job that would be run, and how
+#[derive(Debug, Serialize)]
+pub(crate) struct Step {
+    pub(crate) job_id: String,
+    /// The jobs this one waits for directly, sorted
+    pub(crate) dependencies: Vec<String>,
+    pub(crate) runtime: &'static str,
+    /// After being limited to `max-threads`
+    pub(crate) threads: f32,
+    /// In Docker/Podman format, i.e. `./local/path:/path-in-container`
+    pub(crate) volumes: Vec<String>,
+    /// The program and its arguments
+    pub(crate) command: Vec<String>,
+}
+
+/// Prints the plan for the config at `config_path`
+pub(crate) async fn plan(
+    config_path: String,
+    format: PlanFormat,
+    selection: &JobSelection,
+) -> Result<(), Error> {
+    validate::check(&config_path)?;
+    let state = State::from_file(config_path.clone()).await?;
+    let steps = steps(&state, selection)?;
+
+    match format {
+        PlanFormat::Text => print_text(&config_path, &steps),
+        PlanFormat::Json => println!("{}", serde_json::to_string_pretty(&steps)?),
+        PlanFormat::Dot => print_dot(&steps),
+    }
+    return Ok(());
+}
+
+/// Returns every job `selection` picks, in the order they'd be run, with how they'd be run
+pub(crate) fn steps(state: &State, selection: &JobSelection) -> Result<Vec<Step>, Error> {
+    let graph = state.select(&JobGraph::new(&state.dependency_map)?, selection)?;
+    let env: HashMap<String, String> = HashMap::new();
+
+    let mut steps: Vec<Step> = Vec::new();
+    for job_id in graph.order()? {
+        let job = &state.jobs[&job_id];
+        let spec = job_spec(&state.conf, &job_id, job, RUN_ID_PLACEHOLDER, &env);
+        let cmd = runtime::for_job(&state.conf, job).command(&spec);
+
+        let mut dependencies: Vec<String> =
+            graph.direct_dependencies(&job_id).into_iter().collect();
+        dependencies.sort();
+        steps.push(Step {
+            dependencies,
+            runtime: job.runtime.unwrap_or(state.conf.runtime).name(),
+            threads: spec.threads,
+            volumes: spec.volumes.clone(),
+            command: [cmd.get_program()]
+                .into_iter()
+                .chain(cmd.get_args())
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect(),
+            job_id,
+        });
+    }
+    return Ok(steps);
+}
+
+fn print_text(config_path: &str, steps: &[Step]) {
+    println!(
+        "{} job(s) would be run from {config_path}, in this order:",
+        steps.len()
+    );
+    for (i, step) in steps.iter().enumerate() {
+        println!();
+        println!("{}. {}", i + 1, step.job_id);
+        if step.dependencies.is_empty() {
+            println!("   after: nothing");
+        } else {
+            println!("   after: {}", step.dependencies.join(", "));
+        }
+        println!("   runtime: {}", step.runtime);
+        println!("   threads: {}", step.threads);
+        if step.volumes.is_empty() {
+            println!("   volumes: none");
+        } else {
+            println!("   volumes: {}", step.volumes.join(", "));
+        }
+        let command: Vec<String> = step.command.iter().map(|arg| shell_quote(arg)).collect();
+        println!("   command: {}", command.join(" "));
+    }
+}
+
+/// Prints the jobs as a Graphviz graph, with arrows from each job to the ones that wait for it
+fn print_dot(steps: &[Step]) {
+    println!("digraph gregory {{");
+    println!("    rankdir=LR;");
+    for step in steps {
+        println!(
+            "    {} [label={}];",
+            dot_quote(&step.job_id),
+            dot_quote(&format!("{}\nthreads: {}", step.job_id, step.threads))
+        );
+    }
+    for step in steps {
+        for dependency in &step.dependencies {
+            println!(
+                "    {} -> {};",
+                dot_quote(dependency),
+                dot_quote(&step.job_id)
+            );
+        }
+    }
+    println!("}}");
+}
+
+/// Quotes an argument for a POSIX shell, if it needs it
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_=./:,@%+{}".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_string();
+    }
+    return format!("'{}'", arg.replace('\'', "'\\''"));
+}
+
+/// Quotes a string for DOT
+fn dot_quote(text: &str) -> String {
+    return format!(
+        "\"{}\"",
+        text.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    );
+}
@@ -0,0 +1,340 @@
+//! Commands for looking at what's been run - `status`, `history`, `logs`, and `runs`
+//!
+//! These only read from the database, so they connect without migrating it (see [`storage::connect`]) - i.e. they work with read-only credentials
+
+use crate::cli::OutputFormat;
+use crate::data::Config;
+use crate::errors::Error;
+use crate::logging::parse_log_line;
+use crate::storage::{self, JobRecord, RunRecord, Storage};
+use chrono::{DateTime, Local, Utc};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+/// How often to check for more output with `logs --follow`
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A row with its duration added, for JSON output, since the databases generate that column themselves
+#[derive(Serialize)]
+struct WithDuration<'a, T> {
+    #[serde(flatten)]
+    record: &'a T,
+    /// In seconds
+    duration: Option<f64>,
+}
+
+/// Prints the latest status of every job that's been run
+pub(crate) async fn status(config_path: String, format: OutputFormat) -> Result<(), Error> {
+    let conf = Config::from_file(config_path)?;
+    let storage = storage::connect(&conf).await?;
+    let records = storage.latest_jobs().await?;
+
+    match format {
+        OutputFormat::Json => print_json(&records, JobRecord::duration)?,
+        OutputFormat::Table => print_table(
+            &[
+                "JOB",
+                "STATUS",
+                "STARTED",
+                "DURATION",
+                "EXIT CODE",
+                "REASON",
+            ],
+            records
+                .iter()
+                .map(|record| {
+                    return vec![
+                        record.job_id.clone(),
+                        optional(&record.status),
+                        optional_time(record.start_time),
+                        optional_duration(record.duration()),
+                        optional(&record.exit_code),
+                        optional(&record.reason),
+                    ];
+                })
+                .collect(),
+        ),
+    }
+    return Ok(());
+}
+
+/// Prints the last `limit` runs of a job, newest first
+pub(crate) async fn history(
+    config_path: String,
+    job_id: String,
+    limit: i64,
+    format: OutputFormat,
+) -> Result<(), Error> {
+    let conf = Config::from_file(config_path)?;
+    let storage = storage::connect(&conf).await?;
+    let records = storage.job_history(&job_id, limit).await?;
+
+    match format {
+        OutputFormat::Json => print_json(&records, JobRecord::duration)?,
+        OutputFormat::Table => print_table(
+            &[
+                "STARTED",
+                "STATUS",
+                "DURATION",
+                "EXIT CODE",
+                "ATTEMPT",
+                "REVISION",
+                "VERSION",
+                "UUID",
+            ],
+            records
+                .iter()
+                .map(|record| {
+                    return vec![
+                        optional_time(record.start_time),
+                        optional(&record.status),
+                        optional_duration(record.duration()),
+                        optional(&record.exit_code),
+                        optional(&record.attempt),
+                        record.revision.clone(),
+                        optional(&record.package_version),
+                        record.uuid.to_string(),
+                    ];
+                })
+                .collect(),
+        ),
+    }
+    return Ok(());
+}
+
+/// Prints a job's output - from the latest run of it, unless `run` or `revision` is passed
+///
+/// It's read from the log file if there is one, otherwise from the database (i.e. with `log-files = false`). With `follow`, it keeps checking for more until the job finishes, or its run stops without it finishing (see [`job_over`]).
+pub(crate) async fn logs(
+    config_path: String,
+    job_id: String,
+    run: Option<Uuid>,
+    revision: Option<String>,
+    follow: bool,
+    stream: Option<String>,
+) -> Result<(), Error> {
+    let conf = Config::from_file(config_path)?;
+    let storage = storage::connect(&conf).await?;
+    let mut record = match storage
+        .find_job(&job_id, run.as_ref(), revision.as_deref())
+        .await?
+    {
+        Some(record) => record,
+        None => return Err(Error::NoJobRuns(job_id)),
+    };
+
+    // how far it's got through the log file, or the lines in the database
+    let mut offset: u64 = 0;
+    let mut line_number: i32 = 0;
+    loop {
+        // checked before reading, so nothing written just before it finished gets missed
+        let finished = job_over(storage.as_ref(), &record).await?;
+        match &record.log_path {
+            Some(log_path) if Path::new(log_path).exists() => {
+                offset = print_log_file(log_path, offset, stream.as_deref())?;
+            }
+            _ => {
+                line_number =
+                    print_output_lines(storage.as_ref(), &record, line_number, stream.as_deref())
+                        .await?;
+            }
+        }
+        if !follow || finished {
+            if follow && record.end_time.is_none() {
+                println!("[WARNING] The run stopped before {job_id} finished");
+            }
+            return Ok(());
+        }
+
+        sleep(FOLLOW_INTERVAL).await;
+        if let Some(latest) = storage.find_job(&job_id, Some(&record.uuid), None).await? {
+            record = latest;
+        }
+    }
+}
+
+/// Returns whether there's no more output coming for a job - i.e. it finished, or the run it's part of isn't running any more (e.g. if it was killed before the job finished)
+async fn job_over(storage: &dyn Storage, record: &JobRecord) -> Result<bool, Error> {
+    if record.end_time.is_some() {
+        return Ok(true);
+    }
+    let run_uuid = match record.run_uuid {
+        Some(run_uuid) => run_uuid,
+        None => return Ok(false),
+    };
+    return Ok(match storage.find_run(&run_uuid).await? {
+        Some(run) => run.end_time.is_some() || run.status != "running",
+        None => false,
+    });
+}
+
+/// Prints the lines of a log file after `offset` (in bytes), and returns how far it got
+///
+/// Only whole lines are printed, since the last one might still be being written
+fn print_log_file(log_path: &str, offset: u64, stream: Option<&str>) -> Result<u64, Error> {
+    let mut file = File::open(log_path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut contents: Vec<u8> = Vec::new();
+    file.read_to_end(&mut contents)?;
+    let end = match contents.iter().rposition(|&byte| byte == b'\n') {
+        Some(i) => i + 1,
+        None => 0,
+    };
+
+    for line in String::from_utf8_lossy(&contents[..end]).lines() {
+        let line_stream = parse_log_line(line).map(|(line_stream, _)| line_stream);
+        if stream.is_none_or(|stream| line_stream == Some(stream)) {
+            println!("{line}");
+        }
+    }
+    return Ok(offset + end as u64);
+}
+
+/// Prints a job's output from the database after line `after`, formatted like the log files, and returns the last line number printed
+async fn print_output_lines(
+    storage: &dyn Storage,
+    record: &JobRecord,
+    after: i32,
+    stream: Option<&str>,
+) -> Result<i32, Error> {
+    let mut last = after;
+    for line in storage.job_output_lines(&record.uuid, after).await? {
+        last = line.line_number;
+        if stream.is_some_and(|stream| line.stream != stream) {
+            continue;
+        }
+        let elapsed = line.time - record.start_time.unwrap_or(line.time);
+        println!(
+            "[{:.3}] [{}] {}",
+            elapsed.num_milliseconds() as f64 / 1000.0,
+            line.stream,
+            line.text
+        );
+    }
+    return Ok(last);
+}
+
+/// Prints the last `limit` runs, newest first
+pub(crate) async fn runs(
+    config_path: String,
+    limit: i64,
+    format: OutputFormat,
+) -> Result<(), Error> {
+    let conf = Config::from_file(config_path)?;
+    let storage = storage::connect(&conf).await?;
+    let runs = storage.runs(limit).await?;
+
+    match format {
+        OutputFormat::Json => print_json(&runs, RunRecord::duration)?,
+        OutputFormat::Table => print_table(
+            &[
+                "STARTED",
+                "STATUS",
+                "TRIGGER",
+                "DURATION",
+                "SUCCEEDED",
+                "FAILED",
+                "TIMED OUT",
+                "SKIPPED",
+                "CANCELLED",
+                "NOT RUN",
+                "UUID",
+            ],
+            runs.iter()
+                .map(|run| {
+                    return vec![
+                        optional_time(Some(run.start_time)),
+                        run.status.clone(),
+                        run.trigger.clone(),
+                        optional_duration(run.duration()),
+                        optional(&run.succeeded),
+                        optional(&run.failed),
+                        optional(&run.timed_out),
+                        optional(&run.skipped),
+                        optional(&run.cancelled),
+                        optional(&run.not_run),
+                        run.uuid.to_string(),
+                    ];
+                })
+                .collect(),
+        ),
+    }
+    return Ok(());
+}
+
+/// Prints rows as a JSON array, with their durations added
+fn print_json<T: Serialize>(rows: &[T], duration: fn(&T) -> Option<f64>) -> Result<(), Error> {
+    let rows: Vec<WithDuration<T>> = rows
+        .iter()
+        .map(|record| {
+            return WithDuration {
+                record,
+                duration: duration(record),
+            };
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&rows)?);
+    return Ok(());
+}
+
+/// Prints rows as a table, with the columns lined up
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers
+        .iter()
+        .map(|header| header.chars().count())
+        .collect();
+    for row in &rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+
+    let headers: Vec<String> = headers.iter().map(|header| header.to_string()).collect();
+    for row in [headers].iter().chain(rows.iter()) {
+        let cells: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| format!("{cell:<width$}", width = widths[i]))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+/// Formats something that might be `NULL`, as `-` if it is
+fn optional<T: ToString>(value: &Option<T>) -> String {
+    return match value {
+        Some(value) => value.to_string(),
+        None => "-".to_string(),
+    };
+}
+
+/// Formats a time in the local timezone
+fn optional_time(time: Option<DateTime<Utc>>) -> String {
+    return match time {
+        Some(time) => time
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        None => "-".to_string(),
+    };
+}
+
+/// Formats a duration in seconds, e.g. `4.2s`, `12m 3s`, or `2h 5m`
+pub(crate) fn optional_duration(seconds: Option<f64>) -> String {
+    let seconds = match seconds {
+        Some(seconds) => seconds,
+        None => return "-".to_string(),
+    };
+    let whole_seconds = seconds as u64;
+    if seconds < 60.0 {
+        return format!("{seconds:.1}s");
+    } else if seconds < 3600.0 {
+        return format!("{}m {}s", whole_seconds / 60, whole_seconds % 60);
+    }
+    return format!("{}h {}m", whole_seconds / 3600, whole_seconds % 3600 / 60);
+}
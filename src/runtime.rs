@@ -0,0 +1,197 @@
+//! Container runtimes - what actually runs jobs
+
+use crate::data::{Config, Job, Runtime};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+/// How long jobs get to stop after being asked to before they're killed, in seconds
+const STOP_GRACE_PERIOD: u64 = 10;
+
+/// Everything needed to run a job
+pub(crate) struct JobSpec<'a> {
+    /// The name for the container, i.e. `{job_id}-{run_id}`
+    pub(crate) name: String,
+    /// The job itself
+    pub(crate) job: &'a Job,
+    /// Where the script holding the job's commands is
+    pub(crate) script_path: String,
+    /// How many threads the job gets (see [`Job::capped_threads`])
+    pub(crate) threads: f32,
+    /// The job's volumes, in Docker/Podman format (`./local/path:/path-in-container`)
+    pub(crate) volumes: Vec<String>,
+    /// Environment variables to set for the job
+    pub(crate) env: &'a HashMap<String, String>,
+}
+
+/// Something that can run jobs
+pub(crate) trait ContainerRuntime: Send + Sync {
+    /// Returns the [`Command`] to run a job
+    fn command(&self, spec: &JobSpec) -> Command;
+
+    /// Stops a running job, killing it if it doesn't stop within [`STOP_GRACE_PERIOD`]; blocks until it's stopped
+    fn stop(&self, spec: &JobSpec);
+
+    /// Returns the digest (well, ID) of the local copy of an image, or `None` if it hasn't been pulled (or can't be inspected)
+    fn image_digest(&self, image: &str) -> Option<String>;
+}
+
+/// Runs jobs with Podman
+pub(crate) struct Podman;
+
+/// Runs jobs with Docker
+pub(crate) struct Docker;
+
+/// Runs jobs directly on the host, with no container
+///
+/// `image`, `volumes`, `threads`, and `privileged` are all ignored, and the script is run in the current directory
+pub(crate) struct Host;
+
+impl ContainerRuntime for Podman {
+    fn command(&self, spec: &JobSpec) -> Command {
+        let mut cmd = Command::new("podman");
+        cmd.args(oci_run_args(spec));
+        return cmd;
+    }
+
+    fn stop(&self, spec: &JobSpec) {
+        oci_stop("podman", &spec.name);
+    }
+
+    fn image_digest(&self, image: &str) -> Option<String> {
+        return oci_image_digest("podman", image);
+    }
+}
+
+impl ContainerRuntime for Docker {
+    fn command(&self, spec: &JobSpec) -> Command {
+        let mut cmd = Command::new("docker");
+        cmd.args(oci_run_args(spec));
+        return cmd;
+    }
+
+    fn stop(&self, spec: &JobSpec) {
+        oci_stop("docker", &spec.name);
+    }
+
+    fn image_digest(&self, image: &str) -> Option<String> {
+        return oci_image_digest("docker", image);
+    }
+}
+
+impl ContainerRuntime for Host {
+    fn command(&self, spec: &JobSpec) -> Command {
+        // write the pid to a file so it can be stopped, then run the script in its place
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg("echo $$ > \"$0\" && exec \"$1\" \"$2\"")
+            .arg(host_pid_path(spec))
+            .arg(&spec.job.shell)
+            .arg(&spec.script_path)
+            .envs(spec.env)
+            // give it its own process group, so everything it starts can be stopped along with it
+            .process_group(0);
+        return cmd;
+    }
+
+    fn stop(&self, spec: &JobSpec) {
+        let pid = match fs::read_to_string(host_pid_path(spec)) {
+            Ok(pid) => pid.trim().to_string(),
+            Err(_) => return,
+        };
+        let signal_group = |signal: &str| -> bool {
+            return Command::new("kill")
+                .args([signal, "--", &format!("-{pid}")])
+                .output()
+                .is_ok_and(|output| output.status.success());
+        };
+
+        if !signal_group("-TERM") {
+            return;
+        }
+        for _ in 0..STOP_GRACE_PERIOD {
+            thread::sleep(Duration::from_secs(1));
+            // signal 0 just checks whether it's still running
+            if !signal_group("-0") {
+                return;
+            }
+        }
+        signal_group("-KILL");
+    }
+
+    fn image_digest(&self, _image: &str) -> Option<String> {
+        // there's no image, so it never changes
+        return Some("host".to_string());
+    }
+}
+
+/// Returns the arguments for `podman run`/`docker run`, which take the same arguments (at least the ones used here)
+fn oci_run_args(spec: &JobSpec) -> Vec<String> {
+    let mut cmd_args: Vec<String> = vec![
+        "run".to_string(),
+        "--rm".to_string(),
+        // otherwise pulling the image prints its progress to stderr, mixed in with the job's own - which fails version checks
+        "--quiet".to_string(),
+        format!("--name={}", spec.name),
+        format!("--cpus={}", spec.threads),
+        format!("--privileged={}", spec.job.privileged),
+        format!("--volume={}:/gregory-entrypoint.sh", spec.script_path),
+    ];
+    for volume in &spec.volumes {
+        cmd_args.push(format!("--volume={volume}"));
+    }
+    let mut env: Vec<(&String, &String)> = spec.env.iter().collect();
+    env.sort();
+    for (key, value) in env {
+        cmd_args.push(format!("--env={key}={value}"));
+    }
+    cmd_args.push(format!("--entrypoint={}", spec.job.shell));
+    cmd_args.push(spec.job.image.clone());
+    cmd_args.push("/gregory-entrypoint.sh".to_string());
+
+    return cmd_args;
+}
+
+/// Stops a container using `{binary} stop`, which kills it after the grace period
+fn oci_stop(binary: &str, name: &str) {
+    let _ = Command::new(binary)
+        .args([
+            "stop".to_string(),
+            format!("--time={STOP_GRACE_PERIOD}"),
+            name.to_string(),
+        ])
+        .output();
+}
+
+/// Returns where the host runtime puts a job's pid
+pub(crate) fn host_pid_path(spec: &JobSpec) -> String {
+    return format!("{}.pid", spec.script_path);
+}
+
+/// Returns the ID of an image using `{binary} image inspect`
+fn oci_image_digest(binary: &str, image: &str) -> Option<String> {
+    let output = Command::new(binary)
+        .args(["image", "inspect", "--format={{.Id}}", image])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let digest = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if digest.is_empty() {
+        return None;
+    }
+    return Some(digest);
+}
+
+/// Returns the runtime to use for a job - its own `runtime` if it has one, otherwise the one from the config
+pub(crate) fn for_job(conf: &Config, job: &Job) -> Box<dyn ContainerRuntime> {
+    return match job.runtime.unwrap_or(conf.runtime) {
+        Runtime::Podman => Box::new(Podman),
+        Runtime::Docker => Box::new(Docker),
+        Runtime::Host => Box::new(Host),
+    };
+}
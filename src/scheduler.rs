@@ -0,0 +1,154 @@
+//! Job scheduling - turns the dependency map from [`State`](crate::State) into a graph and works out what order to run things in
+
+use crate::errors::Error;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// A DAG of jobs, built from [`State::dependency_map`](crate::State)
+///
+/// Since the dependency map is recursive, so is this - every job knows *all* the jobs it depends on, not just the direct ones
+#[derive(Debug, Clone)]
+pub(crate) struct JobGraph {
+    /// Maps job ids to the jobs they depend on (recursively)
+    dependencies: HashMap<String, HashSet<String>>,
+    /// Maps job ids to the jobs that depend on them (recursively)
+    dependents: HashMap<String, HashSet<String>>,
+}
+
+impl JobGraph {
+    /// Builds the graph from a dependency map (job id -> job ids which depend on it), erroring if there's a cycle
+    pub(crate) fn new(dependency_map: &HashMap<String, Vec<String>>) -> Result<JobGraph, Error> {
+        let mut dependencies: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut dependents: HashMap<String, HashSet<String>> = HashMap::new();
+
+        for (job_id, deps) in dependency_map {
+            dependencies.entry(job_id.clone()).or_default();
+            dependents
+                .entry(job_id.clone())
+                .or_default()
+                .extend(deps.iter().cloned());
+            for dependent in deps {
+                dependencies
+                    .entry(dependent.clone())
+                    .or_default()
+                    .insert(job_id.clone());
+                dependents.entry(dependent.clone()).or_default();
+            }
+        }
+
+        let graph = JobGraph {
+            dependencies,
+            dependents,
+        };
+        // make sure it's actually sortable
+        graph.order()?;
+
+        return Ok(graph);
+    }
+
+    /// Returns a graph of only the jobs in `job_ids`, with any dependencies on jobs outside of it dropped
+    pub(crate) fn subgraph(&self, job_ids: &HashSet<String>) -> JobGraph {
+        let filter = |map: &HashMap<String, HashSet<String>>| -> HashMap<String, HashSet<String>> {
+            return map
+                .iter()
+                .filter(|(job_id, _)| job_ids.contains(*job_id))
+                .map(|(job_id, others)| {
+                    (
+                        job_id.clone(),
+                        others.intersection(job_ids).cloned().collect(),
+                    )
+                })
+                .collect();
+        };
+
+        return JobGraph {
+            dependencies: filter(&self.dependencies),
+            dependents: filter(&self.dependents),
+        };
+    }
+
+    /// Returns every job that `job_id` depends on (recursively)
+    pub(crate) fn dependencies(&self, job_id: &str) -> HashSet<String> {
+        return self.dependencies.get(job_id).cloned().unwrap_or_default();
+    }
+
+    /// Returns only the jobs `job_id` depends on directly - i.e. not ones that are only there because something else it depends on depends on them
+    pub(crate) fn direct_dependencies(&self, job_id: &str) -> HashSet<String> {
+        let dependencies = self.dependencies(job_id);
+        return dependencies
+            .iter()
+            .filter(|dep| {
+                !dependencies
+                    .iter()
+                    .any(|other| self.dependencies[other].contains(*dep))
+            })
+            .cloned()
+            .collect();
+    }
+
+    /// Returns every job that depends on `job_id` (recursively)
+    pub(crate) fn dependents(&self, job_id: &str) -> HashSet<String> {
+        return self.dependents.get(job_id).cloned().unwrap_or_default();
+    }
+
+    /// Returns the job ids in the order they should be run, so that every job comes after everything it depends on
+    ///
+    /// Uses Kahn's algorithm; ties are broken alphabetically so the order is the same every time
+    pub(crate) fn order(&self) -> Result<Vec<String>, Error> {
+        let mut remaining: HashMap<String, usize> = self
+            .dependencies
+            .iter()
+            .map(|(job_id, deps)| (job_id.clone(), deps.len()))
+            .collect();
+        let mut ready: BTreeSet<String> = remaining
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(job_id, _)| job_id.clone())
+            .collect();
+        let mut ordered: Vec<String> = Vec::new();
+
+        while let Some(job_id) = ready.pop_first() {
+            remaining.remove(&job_id);
+            for dependent in &self.dependents[&job_id] {
+                let count = remaining.get_mut(dependent).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    ready.insert(dependent.clone());
+                }
+            }
+            ordered.push(job_id);
+        }
+
+        if !remaining.is_empty() {
+            return Err(Error::DependencyCycle(
+                self.find_cycle(remaining.into_keys().collect()),
+            ));
+        }
+
+        return Ok(ordered);
+    }
+
+    /// Finds a cycle among `jobs` (which are all the jobs Kahn's algorithm couldn't sort), returned as a path that starts and ends on the same job
+    fn find_cycle(&self, jobs: HashSet<String>) -> Vec<String> {
+        let mut start: Vec<&String> = jobs.iter().collect();
+        start.sort();
+
+        // walk backwards through dependencies that are also stuck until we hit a job we've already seen
+        let mut path: Vec<String> = vec![start[0].clone()];
+        loop {
+            let mut next: Vec<&String> = self.dependencies[path.last().unwrap()]
+                .iter()
+                .filter(|dep| jobs.contains(*dep))
+                .collect();
+            next.sort();
+            let next = next[0].clone();
+
+            if let Some(pos) = path.iter().position(|job_id| *job_id == next) {
+                let mut cycle = path[pos..].to_vec();
+                cycle.push(next);
+                cycle.reverse();
+                return cycle;
+            }
+            path.push(next);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Shutting down cleanly on SIGINT/SIGTERM - see [`handle_signals`]
+
+use crate::storage::{RunRecord, Storage};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::remove_file;
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+/// How long to spend marking the runs as cancelled when exiting immediately, in case the database isn't responding
+const CLOSE_RUNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether the jobs should be stopped, shared between everything that needs to know
+///
+/// Clones all share the same state, and the default is one that hasn't been cancelled
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Cancellation {
+    cancelled: Arc<AtomicBool>,
+    /// Wakes up anything waiting in [`Cancellation::cancelled`]
+    notify: Arc<Notify>,
+    /// Maps the container names of running jobs to their scripts, so they can be cleaned up if gregory has to exit immediately
+    running: Arc<Mutex<HashMap<String, String>>>,
+    /// The runs that are going, so they can be marked as cancelled if gregory has to exit immediately
+    runs: Arc<Mutex<Vec<OpenRun>>>,
+}
+
+/// A run that's been started but hasn't finished, with where it's recorded
+#[derive(Clone)]
+struct OpenRun {
+    storage: Arc<dyn Storage>,
+    run: RunRecord,
+}
+
+impl fmt::Debug for OpenRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "OpenRun({})", self.run.uuid);
+    }
+}
+
+impl Cancellation {
+    /// Cancels everything
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        return self.cancelled.load(Ordering::SeqCst);
+    }
+
+    /// Waits until it's cancelled
+    pub(crate) async fn cancelled(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // registered before checking, so a `cancel` in between isn't missed
+        notified.as_mut().enable();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await;
+    }
+
+    /// Keeps track of a job that's started running, until [`Cancellation::finished`] is called
+    pub(crate) fn started(&self, container_name: &str, script_path: &str) {
+        self.running
+            .lock()
+            .unwrap()
+            .insert(container_name.to_string(), script_path.to_string());
+    }
+
+    pub(crate) fn finished(&self, container_name: &str) {
+        self.running.lock().unwrap().remove(container_name);
+    }
+
+    /// Keeps track of a run that's started, until [`Cancellation::run_finished`] is called
+    pub(crate) fn run_started(&self, storage: Arc<dyn Storage>, run: &RunRecord) {
+        self.runs.lock().unwrap().push(OpenRun {
+            storage,
+            run: run.clone(),
+        });
+    }
+
+    pub(crate) fn run_finished(&self, run_uuid: &Uuid) {
+        self.runs
+            .lock()
+            .unwrap()
+            .retain(|open| open.run.uuid != *run_uuid);
+    }
+
+    /// Marks the runs that haven't finished as cancelled, since they won't get to finish now
+    pub(crate) async fn close_runs(&self) {
+        let runs: Vec<OpenRun> = self.runs.lock().unwrap().drain(..).collect();
+        for OpenRun { storage, mut run } in runs {
+            run.end_time = Some(Utc::now());
+            run.status = "cancelled".to_string();
+            match timeout(CLOSE_RUNS_TIMEOUT, storage.finish_run(&run)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("[ERROR] Couldn't mark run {} as cancelled: {e}", run.uuid),
+                Err(_) => eprintln!(
+                    "[ERROR] Couldn't mark run {} as cancelled: the database took too long",
+                    run.uuid
+                ),
+            }
+        }
+    }
+
+    /// Removes the scripts for the jobs that are still running, and returns their container names
+    fn clean_up(&self) -> Vec<String> {
+        let running = self.running.lock().unwrap();
+        for script_path in running.values() {
+            let _ = remove_file(script_path);
+            let _ = remove_file(format!("{script_path}.pid"));
+        }
+        let mut names: Vec<String> = running.keys().cloned().collect();
+        names.sort();
+        return names;
+    }
+}
+
+/// Cancels `cancellation` on the first SIGINT or SIGTERM, so running jobs are stopped and nothing else is started; on the second one, it cleans up what it can and exits immediately
+pub(crate) fn handle_signals(cancellation: Cancellation) {
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+
+            if !cancellation.is_cancelled() {
+                eprintln!();
+                eprintln!("Stopping the running jobs; press Ctrl-C again to exit immediately");
+                cancellation.cancel();
+                continue;
+            }
+
+            eprintln!("Exiting immediately");
+            let still_running = cancellation.clean_up();
+            if !still_running.is_empty() {
+                eprintln!("These jobs' containers might still be running:");
+                for name in still_running {
+                    eprintln!(" {name}");
+                }
+            }
+            cancellation.close_runs().await;
+            exit(130);
+        }
+    });
+}
@@ -0,0 +1,476 @@
+//! Where run metadata and jobs' output are stored - see [`Storage`]
+//!
+//! Which backend is used is set by the `database` section of the config (see [`Database`])
+
+use crate::data::{Config, Database, DatabaseBackend, JobStatus};
+use crate::errors::Error;
+use crate::executor::Trigger;
+use crate::migrations::Migration;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+mod json_lines;
+mod postgres;
+mod sqlite;
+
+pub(crate) use json_lines::JsonLinesStorage;
+pub(crate) use postgres::PostgresStorage;
+pub(crate) use sqlite::SqliteStorage;
+
+/// Somewhere to record jobs and their output
+///
+/// All the backends have the same tables, with the same semantics, as described in `docs/database.md`
+#[async_trait]
+pub(crate) trait Storage: Send + Sync {
+    /// The migrations for this backend - see [`crate::migrations`]
+    fn migrations(&self) -> &'static [Migration];
+
+    /// Migrates the database to the latest schema version, returning the migrations that were applied
+    ///
+    /// It's all done in one transaction, so it either all works or nothing changes
+    async fn migrate(&self) -> Result<Vec<&'static Migration>, Error>;
+
+    /// Returns the versions of the migrations that have been applied, and when they were applied
+    async fn applied_migrations(&self) -> Result<HashMap<i32, DateTime<Utc>>, Error>;
+
+    /// Returns the database's schema version - 0 if nothing's been migrated yet
+    async fn schema_version(&self) -> Result<i32, Error> {
+        return Ok(self
+            .applied_migrations()
+            .await?
+            .keys()
+            .max()
+            .copied()
+            .unwrap_or(0));
+    }
+
+    /// Adds a row to `job_logs` for a job that's just started, with the status `running`
+    async fn job_started(&self, job: &JobStart) -> Result<(), Error>;
+
+    /// Adds lines to `job_output`
+    async fn log_output(&self, lines: &[OutputLine]) -> Result<(), Error>;
+
+    /// Records that a job finished, filling in the row added when it started (see [`Storage::job_started`])
+    async fn finish_job(
+        &self,
+        uuid: &Uuid,
+        end_time: SystemTime,
+        exit_code: Option<i32>,
+        status: &JobStatus,
+        package_version: Option<String>,
+    ) -> Result<(), Error>;
+
+    /// Logs a job that wasn't started (i.e. [`JobStatus::Skipped`] or [`JobStatus::NotRun`]), and why
+    async fn log_unstarted_job(
+        &self,
+        run_uuid: &Uuid,
+        job_id: &str,
+        revision: &str,
+        status: &JobStatus,
+    ) -> Result<(), Error>;
+
+    /// Returns the lines a job printed to `stream` (`stdout` or `stderr`), from `job_output`
+    async fn job_output(&self, uuid: &Uuid, stream: &str) -> Result<Vec<String>, Error>;
+
+    /// Records the version a package's version check returned
+    async fn log_package_version(
+        &self,
+        package: &str,
+        version: &str,
+        uuid: &Uuid,
+    ) -> Result<(), Error>;
+
+    /// Returns the last version recorded for a package, if there is one
+    async fn last_package_version(&self, package: &str) -> Result<Option<String>, Error>;
+
+    /// Returns the last time a job succeeded, if it's succeeded before
+    async fn last_successful_run(&self, job_id: &str) -> Result<Option<JobRecord>, Error>;
+
+    /// Returns the latest row for each job id, sorted by job id
+    ///
+    /// uuids are v7, so they sort by when they were made - that way skipped jobs, which don't have a start time, are sorted in too
+    async fn latest_jobs(&self) -> Result<Vec<JobRecord>, Error>;
+
+    /// Returns the last `limit` rows for a job, newest first
+    async fn job_history(&self, job_id: &str, limit: i64) -> Result<Vec<JobRecord>, Error>;
+
+    /// Returns the latest row for a job that was actually run (i.e. wasn't skipped), optionally only from a certain run and/or revision
+    ///
+    /// `run` can be the uuid of either the job's row, or the run it was part of
+    async fn find_job(
+        &self,
+        job_id: &str,
+        run: Option<&Uuid>,
+        revision: Option<&str>,
+    ) -> Result<Option<JobRecord>, Error>;
+
+    /// Returns the output from a run of a job that comes after line `after`, from both streams, in order
+    async fn job_output_lines(&self, uuid: &Uuid, after: i32) -> Result<Vec<OutputLine>, Error>;
+
+    /// Adds a row to `runs` for a run that's just started
+    async fn run_started(&self, run: &RunRecord) -> Result<(), Error>;
+
+    /// Records that a run finished, filling in the row added when it started (see [`Storage::run_started`])
+    async fn finish_run(&self, run: &RunRecord) -> Result<(), Error>;
+
+    /// Returns the last `limit` runs, newest first
+    async fn runs(&self, limit: i64) -> Result<Vec<RunRecord>, Error>;
+
+    /// Returns a run by its uuid, if there is one
+    async fn find_run(&self, uuid: &Uuid) -> Result<Option<RunRecord>, Error>;
+
+    /// Returns every row for the jobs in a run (including each attempt, and skipped jobs), oldest first
+    async fn jobs_in_run(&self, run_uuid: &Uuid) -> Result<Vec<JobRecord>, Error>;
+
+    /// Adds a row to `runs` as-is, unless there's already one with the same uuid; returns whether it was added
+    async fn import_run(&self, run: &RunRecord) -> Result<bool, Error>;
+
+    /// Adds a row to `job_logs` as-is, unless there's already one with the same uuid; returns whether it was added
+    async fn import_job(&self, record: &JobRecord) -> Result<bool, Error>;
+
+    /// Adds a row to `package_versions` as-is, unless there's already one with the same uuid; returns whether it was added
+    async fn import_package_version(&self, version: &PackageVersion) -> Result<bool, Error>;
+}
+
+/// Connects to the database set in the config, without migrating it
+pub(crate) async fn connect(conf: &Config) -> Result<Arc<dyn Storage>, Error> {
+    return match conf.database.backend {
+        DatabaseBackend::Postgres => {
+            Ok(Arc::new(PostgresStorage::connect(&conf.database, 5).await?))
+        }
+        DatabaseBackend::Sqlite => Ok(Arc::new(
+            SqliteStorage::open(&sqlite_path(conf, &conf.database)).await?,
+        )),
+        DatabaseBackend::JsonLines => Ok(Arc::new(JsonLinesStorage::open(&json_lines_dir(conf))?)),
+    };
+}
+
+/// Connects to the database set in the config, and migrates it to the latest version if needed (see [`Storage::migrate`])
+pub(crate) async fn start(conf: &Config) -> Result<Arc<dyn Storage>, Error> {
+    let storage = connect(conf).await?;
+    for migration in storage.migrate().await? {
+        println!(
+            "Migrated database to version {} ({})",
+            migration.version, migration.description
+        );
+    }
+    return Ok(storage);
+}
+
+/// Returns where the SQLite database goes - its `path`, or `gregory.db` in the data dir by default
+fn sqlite_path(conf: &Config, database: &Database) -> String {
+    return match &database.path {
+        Some(path) => path.clone(),
+        None => format!("{}/gregory.db", conf.data_dir),
+    };
+}
+
+/// Returns where the JSON lines files go - `path` if JSON lines is the backend, otherwise `no-db` in the data dir (since `path` is for the other backend's database then)
+pub(crate) fn json_lines_dir(conf: &Config) -> String {
+    return match (conf.database.backend, &conf.database.path) {
+        (DatabaseBackend::JsonLines, Some(path)) => path.clone(),
+        _ => format!("{}/no-db", conf.data_dir),
+    };
+}
+
+/// How many rows [`import`] imported, and how many were skipped for already being there
+#[derive(Debug, Default)]
+pub(crate) struct ImportCounts {
+    pub(crate) runs: usize,
+    pub(crate) jobs: usize,
+    pub(crate) existing_jobs: usize,
+    pub(crate) output_lines: usize,
+    pub(crate) package_versions: usize,
+}
+
+/// Imports everything from the JSON lines files written in no-db mode into `storage`
+///
+/// Rows that are already there are skipped, so importing the same files twice is fine
+pub(crate) async fn import(
+    from: &JsonLinesStorage,
+    storage: &dyn Storage,
+) -> Result<ImportCounts, Error> {
+    let mut counts = ImportCounts::default();
+    // runs first, since jobs reference them
+    for run in from.run_records()? {
+        if storage.import_run(&run).await? {
+            counts.runs += 1;
+        }
+    }
+
+    let mut imported: HashSet<Uuid> = HashSet::new();
+    for record in from.job_records()? {
+        if storage.import_job(&record).await? {
+            imported.insert(record.uuid);
+            counts.jobs += 1;
+        } else {
+            counts.existing_jobs += 1;
+        }
+    }
+
+    // only the output of jobs that were just imported, so it's not duplicated
+    let lines: Vec<OutputLine> = from
+        .output_lines()?
+        .into_iter()
+        .filter(|line| imported.contains(&line.uuid))
+        .collect();
+    for lines in lines.chunks(OUTPUT_BATCH_SIZE) {
+        storage.log_output(lines).await?;
+    }
+    counts.output_lines = lines.len();
+
+    for version in from.package_versions()? {
+        if storage.import_package_version(&version).await? {
+            counts.package_versions += 1;
+        }
+    }
+    return Ok(counts);
+}
+
+/// A row from `job_logs`
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+#[allow(dead_code)]
+pub(crate) struct JobRecord {
+    /// `None` for skipped jobs
+    pub(crate) start_time: Option<DateTime<Utc>>,
+    /// `None` for skipped jobs, and jobs that are still running
+    pub(crate) end_time: Option<DateTime<Utc>>,
+    pub(crate) exit_code: Option<i16>,
+    pub(crate) job_id: String,
+    pub(crate) revision: String,
+    pub(crate) uuid: Uuid,
+    pub(crate) log_path: Option<String>,
+    /// See [`JobStatus::name`]; also `running` for jobs that haven't finished, and `None` for rows from before statuses were recorded
+    pub(crate) status: Option<String>,
+    /// Why the job was skipped
+    pub(crate) reason: Option<String>,
+    pub(crate) package_version: Option<String>,
+    pub(crate) image_digest: Option<String>,
+    /// The uuid of the first attempt at the job
+    pub(crate) attempt_group: Option<Uuid>,
+    pub(crate) attempt: Option<i16>,
+    /// The uuid of the run (see [`RunRecord`]) the job was part of; `None` for rows from before runs were recorded
+    pub(crate) run_uuid: Option<Uuid>,
+}
+
+impl JobRecord {
+    /// Returns how long the job took, in seconds, if it's finished
+    pub(crate) fn duration(&self) -> Option<f64> {
+        return seconds_between(self.start_time?, self.end_time?);
+    }
+}
+
+/// The columns of `job_logs` that go in a [`JobRecord`], for `SELECT`s
+const JOB_RECORD_COLUMNS: &str = "start_time, end_time, exit_code, job_id, revision, uuid, log_path, status, reason, package_version, image_digest, attempt_group, attempt, run_uuid";
+
+/// A row from `runs` - one for every time the jobs are run, whether by `gregory run` or on a schedule
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub(crate) struct RunRecord {
+    pub(crate) uuid: Uuid,
+    pub(crate) start_time: DateTime<Utc>,
+    /// `None` while it's still running
+    pub(crate) end_time: Option<DateTime<Utc>>,
+    /// The SHA-256 hash of the config file, to tell which runs had the same config
+    pub(crate) config_hash: String,
+    /// What started the run - see [`Trigger::name`]
+    pub(crate) trigger: String,
+    /// `running`, `cancelled` if it was interrupted, `failed` if any jobs failed or timed out, otherwise `succeeded`
+    pub(crate) status: String,
+    /// How many jobs ended up with each status; `None` while it's still running
+    pub(crate) succeeded: Option<i32>,
+    pub(crate) failed: Option<i32>,
+    pub(crate) timed_out: Option<i32>,
+    pub(crate) skipped: Option<i32>,
+    pub(crate) cancelled: Option<i32>,
+    pub(crate) not_run: Option<i32>,
+    /// The run this one carried on with, if it was started with `run --resume`
+    pub(crate) resumed_from: Option<Uuid>,
+}
+
+/// The columns of `runs` that go in a [`RunRecord`], for `SELECT`s
+const RUN_RECORD_COLUMNS: &str =
+    "uuid, start_time, end_time, config_hash, trigger, status, succeeded, failed, timed_out, skipped, cancelled, not_run, resumed_from";
+
+impl RunRecord {
+    /// Returns the record for a run that's starting now, carrying on with `resumed_from` if it's set
+    pub(crate) fn start(
+        config_hash: String,
+        trigger: Trigger,
+        resumed_from: Option<Uuid>,
+    ) -> RunRecord {
+        return RunRecord {
+            uuid: Uuid::now_v7(),
+            start_time: Utc::now(),
+            end_time: None,
+            config_hash,
+            trigger: trigger.name().to_string(),
+            status: "running".to_string(),
+            succeeded: None,
+            failed: None,
+            timed_out: None,
+            skipped: None,
+            cancelled: None,
+            not_run: None,
+            resumed_from,
+        };
+    }
+
+    /// Returns how long the run took, in seconds, if it's finished
+    pub(crate) fn duration(&self) -> Option<f64> {
+        return seconds_between(self.start_time, self.end_time?);
+    }
+
+    /// Fills in the end time, status, and counts from what happened to each job
+    pub(crate) fn finish(&mut self, statuses: &HashMap<String, JobStatus>) {
+        let count = |matches: fn(&JobStatus) -> bool| -> Option<i32> {
+            return Some(statuses.values().filter(|status| matches(status)).count() as i32);
+        };
+        self.end_time = Some(Utc::now());
+        self.succeeded = count(|status| *status == JobStatus::Succeeded);
+        self.failed = count(|status| *status == JobStatus::Failed);
+        self.timed_out = count(|status| *status == JobStatus::TimedOut);
+        self.skipped = count(|status| matches!(status, JobStatus::Skipped(_)));
+        self.cancelled = count(|status| *status == JobStatus::Cancelled);
+        self.not_run = count(|status| matches!(status, JobStatus::NotRun(_)));
+        self.status = if self.cancelled > Some(0) || self.not_run > Some(0) {
+            "cancelled".to_string()
+        } else if statuses.values().any(|status| status.is_failure()) {
+            "failed".to_string()
+        } else {
+            "succeeded".to_string()
+        };
+    }
+}
+
+/// Returns the time between `start` and `end`, in seconds
+fn seconds_between(start: DateTime<Utc>, end: DateTime<Utc>) -> Option<f64> {
+    return Some((end - start).num_microseconds()? as f64 / 1_000_000.0);
+}
+
+/// The details of a job that's just started
+pub(crate) struct JobStart {
+    pub(crate) uuid: Uuid,
+    /// The uuid of the run it's part of
+    pub(crate) run_uuid: Uuid,
+    pub(crate) start_time: SystemTime,
+    pub(crate) job_id: String,
+    pub(crate) revision: String,
+    /// The uuid of the first attempt at the job
+    pub(crate) attempt_group: Uuid,
+    pub(crate) attempt: u32,
+    pub(crate) log_path: Option<String>,
+    pub(crate) image_digest: Option<String>,
+}
+
+/// A line of a job's output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct OutputLine {
+    /// The uuid of the run of the job it's from
+    pub(crate) uuid: Uuid,
+    /// Counts up from 1, across both stdout and stderr
+    pub(crate) line_number: i32,
+    /// `stdout` or `stderr`
+    pub(crate) stream: String,
+    pub(crate) time: DateTime<Utc>,
+    pub(crate) text: String,
+}
+
+/// A row from `package_versions`
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub(crate) struct PackageVersion {
+    pub(crate) package: String,
+    pub(crate) version: String,
+    pub(crate) checked_at: DateTime<Utc>,
+    /// The uuid of the version check's run
+    pub(crate) uuid: Uuid,
+}
+
+enum OutputMessage {
+    JobStarted(JobStart),
+    Line(OutputLine),
+    /// Sends back once everything before it has been written
+    Flush(oneshot::Sender<()>),
+}
+
+/// The most lines written to the database in one go
+const OUTPUT_BATCH_SIZE: usize = 1000;
+
+/// Writes jobs' output to the database in the background, so jobs don't have to wait on it, and so they show up in the database while they're still running
+///
+/// Lines are written in batches of whatever's come in since the last batch was written, up to [`OUTPUT_BATCH_SIZE`]
+#[derive(Clone)]
+pub(crate) struct OutputWriter {
+    sender: mpsc::UnboundedSender<OutputMessage>,
+}
+
+impl OutputWriter {
+    /// Starts writing output to `storage`
+    ///
+    /// The returned task finishes once every copy of the writer has been dropped and everything's been written, or as soon as writing something fails, with the error
+    pub(crate) fn start(
+        storage: Arc<dyn Storage>,
+    ) -> (OutputWriter, JoinHandle<Result<(), Error>>) {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            let mut messages: Vec<OutputMessage> = Vec::new();
+            let mut lines: Vec<OutputLine> = Vec::new();
+            while receiver.recv_many(&mut messages, OUTPUT_BATCH_SIZE).await > 0 {
+                for message in messages.drain(..) {
+                    match message {
+                        OutputMessage::Line(line) => lines.push(line),
+                        OutputMessage::JobStarted(job) => {
+                            write_lines(storage.as_ref(), &mut lines).await?;
+                            storage.job_started(&job).await?;
+                        }
+                        OutputMessage::Flush(done) => {
+                            write_lines(storage.as_ref(), &mut lines).await?;
+                            let _ = done.send(());
+                        }
+                    }
+                }
+                write_lines(storage.as_ref(), &mut lines).await?;
+            }
+            return Ok(());
+        });
+        return (OutputWriter { sender }, task);
+    }
+
+    /// Adds a row to `job_logs` for a job that's just started - see [`Storage::job_started`]
+    pub(crate) fn job_started(&self, job: JobStart) {
+        let _ = self.sender.send(OutputMessage::JobStarted(job));
+    }
+
+    /// Queues a line of output to be written
+    pub(crate) fn line(&self, line: OutputLine) {
+        let _ = self.sender.send(OutputMessage::Line(line));
+    }
+
+    /// Waits until everything sent so far has been written
+    ///
+    /// Returns [`Error::OutputWriterStopped`] if it's stopped because something failed to be written - the actual error is returned by the task from [`OutputWriter::start`]
+    pub(crate) async fn flush(&self) -> Result<(), Error> {
+        let (done_sender, done_receiver) = oneshot::channel();
+        if self.sender.send(OutputMessage::Flush(done_sender)).is_err() {
+            return Err(Error::OutputWriterStopped);
+        }
+        return done_receiver.await.map_err(|_| Error::OutputWriterStopped);
+    }
+}
+
+/// Writes lines to `storage`, emptying `lines`
+async fn write_lines(storage: &dyn Storage, lines: &mut Vec<OutputLine>) -> Result<(), Error> {
+    if lines.is_empty() {
+        return Ok(());
+    }
+    storage.log_output(lines).await?;
+    lines.clear();
+    return Ok(());
+}
@@ -0,0 +1,392 @@
+//! The JSON lines backend, for running without a database (i.e. `gregory run --no-db`)
+//!
+//! Each table is a file in the same directory (e.g. `job_logs.jsonl`), with one JSON object per line, with the same fields as the table. Lines are only ever appended, so when a job finishes its row is written again with everything filled in - the last line with a uuid is the current one. They can be imported into a database later with `gregory db import` (see [`super::import`]).
+
+use super::{JobRecord, JobStart, OutputLine, PackageVersion, RunRecord, Storage};
+use crate::data::JobStatus;
+use crate::errors::Error;
+use crate::migrations::Migration;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, create_dir_all, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// Stores everything in JSON lines files in a directory
+pub(crate) struct JsonLinesStorage {
+    dir: String,
+    /// Held while appending, so lines written at the same time don't get mixed up
+    write_lock: Mutex<()>,
+}
+
+/// A line of `job_logs.jsonl` - a [`JobRecord`], plus the columns the databases generate themselves
+#[derive(Serialize)]
+struct JobLogLine<'a> {
+    #[serde(flatten)]
+    record: &'a JobRecord,
+    /// In seconds
+    duration: Option<f64>,
+    container_name: String,
+}
+
+impl JsonLinesStorage {
+    /// Opens the files in `dir`, creating it if it doesn't exist
+    pub(crate) fn open(dir: &str) -> Result<JsonLinesStorage, Error> {
+        create_dir_all(dir)?;
+        return Ok(JsonLinesStorage {
+            dir: dir.to_string(),
+            write_lock: Mutex::new(()),
+        });
+    }
+
+    /// Returns the rows in `job_logs.jsonl` - only the last line for each uuid, in the order they were first written
+    pub(crate) fn job_records(&self) -> Result<Vec<JobRecord>, Error> {
+        return Ok(last_by_uuid(
+            self.read("job_logs")?,
+            |record: &JobRecord| record.uuid,
+        ));
+    }
+
+    /// Returns the rows in `runs.jsonl` - only the last line for each uuid, in the order they were first written
+    pub(crate) fn run_records(&self) -> Result<Vec<RunRecord>, Error> {
+        return Ok(last_by_uuid(self.read("runs")?, |run: &RunRecord| run.uuid));
+    }
+
+    /// Returns the rows in `job_output.jsonl`
+    pub(crate) fn output_lines(&self) -> Result<Vec<OutputLine>, Error> {
+        return self.read("job_output");
+    }
+
+    /// Returns the rows in `package_versions.jsonl`
+    pub(crate) fn package_versions(&self) -> Result<Vec<PackageVersion>, Error> {
+        return self.read("package_versions");
+    }
+
+    /// Returns the path of the file for `table`
+    fn path(&self, table: &str) -> String {
+        return format!("{}/{table}.jsonl", self.dir);
+    }
+
+    /// Appends `rows` to the file for `table`
+    fn append<T: Serialize>(&self, table: &str, rows: &[T]) -> Result<(), Error> {
+        let mut lines = String::new();
+        for row in rows {
+            lines.push_str(&serde_json::to_string(row)?);
+            lines.push('\n');
+        }
+
+        let _lock = self.write_lock.lock().unwrap();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(table))?
+            .write_all(lines.as_bytes())?;
+        return Ok(());
+    }
+
+    /// Reads every row from the file for `table`; it's fine if it doesn't exist yet
+    fn read<T: DeserializeOwned>(&self, table: &str) -> Result<Vec<T>, Error> {
+        let contents = match fs::read_to_string(self.path(table)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::IOError(e)),
+        };
+        let mut rows: Vec<T> = Vec::new();
+        for line in contents.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            rows.push(serde_json::from_str(line)?);
+        }
+        return Ok(rows);
+    }
+
+    /// Writes a row to `job_logs.jsonl`
+    fn write_job(&self, record: &JobRecord) -> Result<(), Error> {
+        return self.append(
+            "job_logs",
+            &[JobLogLine {
+                record,
+                duration: record.duration(),
+                container_name: format!("{}-{}", record.job_id, record.uuid),
+            }],
+        );
+    }
+}
+
+#[async_trait]
+impl Storage for JsonLinesStorage {
+    /// There's no schema to migrate
+    fn migrations(&self) -> &'static [Migration] {
+        return &[];
+    }
+
+    async fn migrate(&self) -> Result<Vec<&'static Migration>, Error> {
+        return Ok(Vec::new());
+    }
+
+    async fn applied_migrations(&self) -> Result<HashMap<i32, DateTime<Utc>>, Error> {
+        return Ok(HashMap::new());
+    }
+
+    async fn job_started(&self, job: &JobStart) -> Result<(), Error> {
+        return self.write_job(&JobRecord {
+            start_time: Some(job.start_time.into()),
+            end_time: None,
+            exit_code: None,
+            job_id: job.job_id.clone(),
+            revision: job.revision.clone(),
+            uuid: job.uuid,
+            log_path: job.log_path.clone(),
+            status: Some("running".to_string()),
+            reason: None,
+            package_version: None,
+            image_digest: job.image_digest.clone(),
+            attempt_group: Some(job.attempt_group),
+            attempt: Some(job.attempt as i16),
+            run_uuid: Some(job.run_uuid),
+        });
+    }
+
+    async fn log_output(&self, lines: &[OutputLine]) -> Result<(), Error> {
+        return self.append("job_output", lines);
+    }
+
+    async fn finish_job(
+        &self,
+        uuid: &Uuid,
+        end_time: SystemTime,
+        exit_code: Option<i32>,
+        status: &JobStatus,
+        package_version: Option<String>,
+    ) -> Result<(), Error> {
+        // like an `UPDATE` that doesn't match anything, it's fine if there's no row for it
+        let mut record = match self
+            .job_records()?
+            .into_iter()
+            .find(|record| record.uuid == *uuid)
+        {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+        record.end_time = Some(end_time.into());
+        record.exit_code = exit_code.map(|code| code as i16);
+        record.status = Some(status.name().to_string());
+        record.package_version = package_version;
+        return self.write_job(&record);
+    }
+
+    async fn log_unstarted_job(
+        &self,
+        run_uuid: &Uuid,
+        job_id: &str,
+        revision: &str,
+        status: &JobStatus,
+    ) -> Result<(), Error> {
+        return self.write_job(&JobRecord {
+            start_time: None,
+            end_time: None,
+            exit_code: None,
+            job_id: job_id.to_string(),
+            revision: revision.to_string(),
+            uuid: Uuid::now_v7(),
+            log_path: None,
+            status: Some(status.name().to_string()),
+            reason: status.reason().map(|reason| reason.to_string()),
+            package_version: None,
+            image_digest: None,
+            attempt_group: None,
+            attempt: None,
+            run_uuid: Some(*run_uuid),
+        });
+    }
+
+    async fn job_output(&self, uuid: &Uuid, stream: &str) -> Result<Vec<String>, Error> {
+        let mut lines: Vec<OutputLine> = self
+            .output_lines()?
+            .into_iter()
+            .filter(|line| line.uuid == *uuid && line.stream == stream)
+            .collect();
+        lines.sort_by_key(|line| line.line_number);
+        return Ok(lines.into_iter().map(|line| line.text).collect());
+    }
+
+    async fn log_package_version(
+        &self,
+        package: &str,
+        version: &str,
+        uuid: &Uuid,
+    ) -> Result<(), Error> {
+        return self.append(
+            "package_versions",
+            &[PackageVersion {
+                package: package.to_string(),
+                version: version.to_string(),
+                checked_at: Utc::now(),
+                uuid: *uuid,
+            }],
+        );
+    }
+
+    async fn last_package_version(&self, package: &str) -> Result<Option<String>, Error> {
+        return Ok(self
+            .package_versions()?
+            .into_iter()
+            .filter(|version| version.package == package)
+            .max_by_key(|version| version.checked_at)
+            .map(|version| version.version));
+    }
+
+    async fn last_successful_run(&self, job_id: &str) -> Result<Option<JobRecord>, Error> {
+        return Ok(self
+            .job_records()?
+            .into_iter()
+            .filter(|record| {
+                record.job_id == job_id && record.status.as_deref() == Some("succeeded")
+            })
+            .max_by_key(|record| record.start_time));
+    }
+
+    async fn latest_jobs(&self) -> Result<Vec<JobRecord>, Error> {
+        let mut latest: HashMap<String, JobRecord> = HashMap::new();
+        for record in self.job_records()? {
+            match latest.get(&record.job_id) {
+                Some(existing) if existing.uuid > record.uuid => {}
+                _ => {
+                    latest.insert(record.job_id.clone(), record);
+                }
+            }
+        }
+        let mut records: Vec<JobRecord> = latest.into_values().collect();
+        records.sort_by(|a, b| a.job_id.cmp(&b.job_id));
+        return Ok(records);
+    }
+
+    async fn job_history(&self, job_id: &str, limit: i64) -> Result<Vec<JobRecord>, Error> {
+        let mut records: Vec<JobRecord> = self
+            .job_records()?
+            .into_iter()
+            .filter(|record| record.job_id == job_id)
+            .collect();
+        records.sort_by_key(|record| std::cmp::Reverse(record.uuid));
+        records.truncate(limit.max(0) as usize);
+        return Ok(records);
+    }
+
+    async fn find_job(
+        &self,
+        job_id: &str,
+        run: Option<&Uuid>,
+        revision: Option<&str>,
+    ) -> Result<Option<JobRecord>, Error> {
+        return Ok(self
+            .job_records()?
+            .into_iter()
+            .filter(|record| {
+                record.job_id == job_id
+                    && record.start_time.is_some()
+                    && run.is_none_or(|run| record.uuid == *run || record.run_uuid == Some(*run))
+                    && revision.is_none_or(|revision| record.revision == revision)
+            })
+            .max_by_key(|record| record.uuid));
+    }
+
+    async fn job_output_lines(&self, uuid: &Uuid, after: i32) -> Result<Vec<OutputLine>, Error> {
+        let mut lines: Vec<OutputLine> = self
+            .output_lines()?
+            .into_iter()
+            .filter(|line| line.uuid == *uuid && line.line_number > after)
+            .collect();
+        lines.sort_by_key(|line| line.line_number);
+        return Ok(lines);
+    }
+
+    async fn run_started(&self, run: &RunRecord) -> Result<(), Error> {
+        return self.append("runs", std::slice::from_ref(run));
+    }
+
+    async fn finish_run(&self, run: &RunRecord) -> Result<(), Error> {
+        return self.append("runs", std::slice::from_ref(run));
+    }
+
+    async fn runs(&self, limit: i64) -> Result<Vec<RunRecord>, Error> {
+        let mut runs = self.run_records()?;
+        runs.sort_by_key(|run| std::cmp::Reverse(run.start_time));
+        runs.truncate(limit.max(0) as usize);
+        return Ok(runs);
+    }
+
+    async fn find_run(&self, uuid: &Uuid) -> Result<Option<RunRecord>, Error> {
+        return Ok(self
+            .run_records()?
+            .into_iter()
+            .find(|run| run.uuid == *uuid));
+    }
+
+    async fn jobs_in_run(&self, run_uuid: &Uuid) -> Result<Vec<JobRecord>, Error> {
+        let mut records: Vec<JobRecord> = self
+            .job_records()?
+            .into_iter()
+            .filter(|record| record.run_uuid == Some(*run_uuid))
+            .collect();
+        records.sort_by_key(|record| record.uuid);
+        return Ok(records);
+    }
+
+    async fn import_run(&self, run: &RunRecord) -> Result<bool, Error> {
+        if self
+            .run_records()?
+            .iter()
+            .any(|existing| existing.uuid == run.uuid)
+        {
+            return Ok(false);
+        }
+        self.append("runs", std::slice::from_ref(run))?;
+        return Ok(true);
+    }
+
+    async fn import_job(&self, record: &JobRecord) -> Result<bool, Error> {
+        if self
+            .job_records()?
+            .iter()
+            .any(|existing| existing.uuid == record.uuid)
+        {
+            return Ok(false);
+        }
+        self.write_job(record)?;
+        return Ok(true);
+    }
+
+    async fn import_package_version(&self, version: &PackageVersion) -> Result<bool, Error> {
+        if self
+            .package_versions()?
+            .iter()
+            .any(|existing| existing.uuid == version.uuid)
+        {
+            return Ok(false);
+        }
+        self.append("package_versions", std::slice::from_ref(version))?;
+        return Ok(true);
+    }
+}
+
+/// Returns only the last row for each uuid, in the order they first appear, since rows are written again whenever they change
+fn last_by_uuid<T>(rows: Vec<T>, uuid: fn(&T) -> Uuid) -> Vec<T> {
+    let mut last: Vec<T> = Vec::new();
+    let mut indexes: HashMap<Uuid, usize> = HashMap::new();
+    for row in rows {
+        match indexes.get(&uuid(&row)) {
+            Some(&i) => last[i] = row,
+            None => {
+                indexes.insert(uuid(&row), last.len());
+                last.push(row);
+            }
+        }
+    }
+    return last;
+}
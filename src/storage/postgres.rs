@@ -0,0 +1,535 @@
+//! The PostgreSQL backend
+
+use super::{
+    JobRecord, JobStart, OutputLine, PackageVersion, RunRecord, Storage, JOB_RECORD_COLUMNS,
+    RUN_RECORD_COLUMNS,
+};
+use crate::data::{Database, JobStatus};
+use crate::errors::Error;
+use crate::migrations::{self, Migration};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions, PgSslMode};
+use sqlx::{Executor, Postgres, QueryBuilder};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// Arbitrary id for the lock held while migrating, so that only one thing migrates at once (it's "gregory" in ASCII)
+const MIGRATION_LOCK: i64 = 0x67_72_65_67_6f_72_79;
+
+/// Stores everything in postgres
+pub(crate) struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    /// Connects to postgres, using the settings from [`connect_options`]
+    ///
+    /// *x*: How many times to retry if connecting fails
+    pub(crate) async fn connect(database: &Database, x: u16) -> Result<PostgresStorage, Error> {
+        let options = connect_options(database, &env::vars().collect())?;
+        let mut pool = PgPoolOptions::new().connect_with(options.clone()).await;
+        for _ in 0..x {
+            if pool.is_ok() {
+                break;
+            }
+            pool = PgPoolOptions::new().connect_with(options.clone()).await;
+        }
+        return Ok(PostgresStorage { pool: pool? });
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    fn migrations(&self) -> &'static [Migration] {
+        return migrations::POSTGRES;
+    }
+
+    /// Also holds a lock while migrating, so two runs starting at once (e.g. in daemon mode) don't both try to migrate it
+    async fn migrate(&self) -> Result<Vec<&'static Migration>, Error> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(MIGRATION_LOCK)
+            .execute(transaction.as_mut())
+            .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+    version     integer PRIMARY KEY,
+    description text,
+    applied_at  timestamptz
+);
+",
+        )
+        .execute(transaction.as_mut())
+        .await?;
+
+        let version: i32 =
+            sqlx::query_scalar("SELECT coalesce(max(version), 0) FROM schema_version")
+                .fetch_one(transaction.as_mut())
+                .await?;
+        let latest_version = migrations::latest_version(migrations::POSTGRES);
+        if version > latest_version {
+            return Err(Error::SchemaTooNew(version, latest_version));
+        }
+
+        let mut applied: Vec<&'static Migration> = Vec::new();
+        for migration in migrations::POSTGRES {
+            if migration.version <= version {
+                continue;
+            }
+            // a plain `&str` can hold multiple statements, unlike `sqlx::query`
+            transaction.as_mut().execute(migration.sql).await?;
+            sqlx::query("INSERT INTO schema_version (version, description, applied_at) VALUES ($1, $2, now())")
+                .bind(migration.version)
+                .bind(migration.description)
+                .execute(transaction.as_mut())
+                .await?;
+            applied.push(migration);
+        }
+
+        transaction.commit().await?;
+        return Ok(applied);
+    }
+
+    async fn applied_migrations(&self) -> Result<HashMap<i32, DateTime<Utc>>, Error> {
+        let exists: bool = sqlx::query_scalar("SELECT to_regclass('schema_version') IS NOT NULL")
+            .fetch_one(&self.pool)
+            .await?;
+        if !exists {
+            return Ok(HashMap::new());
+        }
+
+        let applied: Vec<(i32, DateTime<Utc>)> =
+            sqlx::query_as("SELECT version, applied_at FROM schema_version")
+                .fetch_all(&self.pool)
+                .await?;
+        return Ok(applied.into_iter().collect());
+    }
+
+    async fn job_started(&self, job: &JobStart) -> Result<(), Error> {
+        sqlx::query("INSERT INTO job_logs (start_time, job_id, revision, uuid, attempt_group, attempt, log_path, status, image_digest, run_uuid) VALUES ($1, $2, $3, $4, $5, $6, $7, 'running', $8, $9)")
+            .bind(DateTime::<Utc>::from(job.start_time))
+            .bind(&job.job_id)
+            .bind(&job.revision)
+            .bind(job.uuid)
+            .bind(job.attempt_group)
+            .bind(job.attempt as i16)
+            .bind(&job.log_path)
+            .bind(&job.image_digest)
+            .bind(job.run_uuid)
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn log_output(&self, lines: &[OutputLine]) -> Result<(), Error> {
+        let mut query = QueryBuilder::<Postgres>::new(
+            "INSERT INTO job_output (uuid, line_number, stream, time, text) ",
+        );
+        query.push_values(lines, |mut row, line| {
+            row.push_bind(line.uuid)
+                .push_bind(line.line_number)
+                .push_bind(&line.stream)
+                .push_bind(line.time)
+                .push_bind(&line.text);
+        });
+        query.build().execute(&self.pool).await?;
+        return Ok(());
+    }
+
+    async fn finish_job(
+        &self,
+        uuid: &Uuid,
+        end_time: SystemTime,
+        exit_code: Option<i32>,
+        status: &JobStatus,
+        package_version: Option<String>,
+    ) -> Result<(), Error> {
+        sqlx::query("UPDATE job_logs SET end_time = $1, exit_code = $2, status = $3, package_version = $4 WHERE uuid = $5")
+            .bind(DateTime::<Utc>::from(end_time))
+            .bind(exit_code.map(|code| code as i16))
+            .bind(status.name())
+            .bind(package_version)
+            .bind(uuid)
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn log_unstarted_job(
+        &self,
+        run_uuid: &Uuid,
+        job_id: &str,
+        revision: &str,
+        status: &JobStatus,
+    ) -> Result<(), Error> {
+        sqlx::query("INSERT INTO job_logs (job_id, revision, uuid, status, reason, run_uuid) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(job_id)
+            .bind(revision)
+            .bind(Uuid::now_v7())
+            .bind(status.name())
+            .bind(status.reason())
+            .bind(run_uuid)
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn job_output(&self, uuid: &Uuid, stream: &str) -> Result<Vec<String>, Error> {
+        let lines: Vec<String> = sqlx::query_scalar(
+            "SELECT text FROM job_output WHERE uuid = $1 AND stream = $2 ORDER BY line_number",
+        )
+        .bind(uuid)
+        .bind(stream)
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(lines);
+    }
+
+    async fn log_package_version(
+        &self,
+        package: &str,
+        version: &str,
+        uuid: &Uuid,
+    ) -> Result<(), Error> {
+        sqlx::query("INSERT INTO package_versions (package, version, checked_at, uuid) VALUES ($1, $2, now(), $3)")
+            .bind(package)
+            .bind(version)
+            .bind(uuid)
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn last_package_version(&self, package: &str) -> Result<Option<String>, Error> {
+        let version: Option<String> = sqlx::query_scalar(
+            "SELECT version FROM package_versions WHERE package = $1 ORDER BY checked_at DESC LIMIT 1",
+        )
+        .bind(package)
+        .fetch_optional(&self.pool)
+        .await?;
+        return Ok(version);
+    }
+
+    async fn last_successful_run(&self, job_id: &str) -> Result<Option<JobRecord>, Error> {
+        let record: Option<JobRecord> = sqlx::query_as(&format!(
+            "SELECT {JOB_RECORD_COLUMNS} FROM job_logs WHERE job_id = $1 AND status = 'succeeded' ORDER BY start_time DESC LIMIT 1"
+        ))
+        .bind(job_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        return Ok(record);
+    }
+
+    async fn latest_jobs(&self) -> Result<Vec<JobRecord>, Error> {
+        let records: Vec<JobRecord> = sqlx::query_as(&format!(
+            "SELECT DISTINCT ON (job_id) {JOB_RECORD_COLUMNS} FROM job_logs ORDER BY job_id, uuid DESC"
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(records);
+    }
+
+    async fn job_history(&self, job_id: &str, limit: i64) -> Result<Vec<JobRecord>, Error> {
+        let records: Vec<JobRecord> = sqlx::query_as(&format!(
+            "SELECT {JOB_RECORD_COLUMNS} FROM job_logs WHERE job_id = $1 ORDER BY uuid DESC LIMIT $2"
+        ))
+        .bind(job_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(records);
+    }
+
+    async fn find_job(
+        &self,
+        job_id: &str,
+        run: Option<&Uuid>,
+        revision: Option<&str>,
+    ) -> Result<Option<JobRecord>, Error> {
+        let record: Option<JobRecord> = sqlx::query_as(&format!(
+            "SELECT {JOB_RECORD_COLUMNS} FROM job_logs WHERE job_id = $1 AND start_time IS NOT NULL AND ($2::uuid IS NULL OR uuid = $2 OR run_uuid = $2) AND ($3::text IS NULL OR revision = $3) ORDER BY uuid DESC LIMIT 1"
+        ))
+        .bind(job_id)
+        .bind(run)
+        .bind(revision)
+        .fetch_optional(&self.pool)
+        .await?;
+        return Ok(record);
+    }
+
+    async fn job_output_lines(&self, uuid: &Uuid, after: i32) -> Result<Vec<OutputLine>, Error> {
+        let lines: Vec<(i32, String, DateTime<Utc>, String)> = sqlx::query_as(
+            "SELECT line_number, stream, time, text FROM job_output WHERE uuid = $1 AND line_number > $2 ORDER BY line_number",
+        )
+        .bind(uuid)
+        .bind(after)
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(lines
+            .into_iter()
+            .map(|(line_number, stream, time, text)| OutputLine {
+                uuid: *uuid,
+                line_number,
+                stream,
+                time,
+                text,
+            })
+            .collect());
+    }
+
+    async fn run_started(&self, run: &RunRecord) -> Result<(), Error> {
+        sqlx::query("INSERT INTO runs (uuid, start_time, config_hash, trigger, status, resumed_from) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(run.uuid)
+            .bind(run.start_time)
+            .bind(&run.config_hash)
+            .bind(&run.trigger)
+            .bind(&run.status)
+            .bind(run.resumed_from)
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn finish_run(&self, run: &RunRecord) -> Result<(), Error> {
+        sqlx::query("UPDATE runs SET end_time = $1, status = $2, succeeded = $3, failed = $4, timed_out = $5, skipped = $6, cancelled = $7, not_run = $8 WHERE uuid = $9")
+            .bind(run.end_time)
+            .bind(&run.status)
+            .bind(run.succeeded)
+            .bind(run.failed)
+            .bind(run.timed_out)
+            .bind(run.skipped)
+            .bind(run.cancelled)
+            .bind(run.not_run)
+            .bind(run.uuid)
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn runs(&self, limit: i64) -> Result<Vec<RunRecord>, Error> {
+        let runs: Vec<RunRecord> = sqlx::query_as(&format!(
+            "SELECT {RUN_RECORD_COLUMNS} FROM runs ORDER BY start_time DESC LIMIT $1"
+        ))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(runs);
+    }
+
+    async fn find_run(&self, uuid: &Uuid) -> Result<Option<RunRecord>, Error> {
+        let run: Option<RunRecord> = sqlx::query_as(&format!(
+            "SELECT {RUN_RECORD_COLUMNS} FROM runs WHERE uuid = $1"
+        ))
+        .bind(uuid)
+        .fetch_optional(&self.pool)
+        .await?;
+        return Ok(run);
+    }
+
+    async fn jobs_in_run(&self, run_uuid: &Uuid) -> Result<Vec<JobRecord>, Error> {
+        let records: Vec<JobRecord> = sqlx::query_as(&format!(
+            "SELECT {JOB_RECORD_COLUMNS} FROM job_logs WHERE run_uuid = $1 ORDER BY uuid"
+        ))
+        .bind(run_uuid)
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(records);
+    }
+
+    async fn import_run(&self, run: &RunRecord) -> Result<bool, Error> {
+        let result = sqlx::query(&format!(
+            "INSERT INTO runs ({RUN_RECORD_COLUMNS}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) ON CONFLICT (uuid) DO NOTHING"
+        ))
+        .bind(run.uuid)
+        .bind(run.start_time)
+        .bind(run.end_time)
+        .bind(&run.config_hash)
+        .bind(&run.trigger)
+        .bind(&run.status)
+        .bind(run.succeeded)
+        .bind(run.failed)
+        .bind(run.timed_out)
+        .bind(run.skipped)
+        .bind(run.cancelled)
+        .bind(run.not_run)
+        .bind(run.resumed_from)
+        .execute(&self.pool)
+        .await?;
+        return Ok(result.rows_affected() > 0);
+    }
+
+    async fn import_job(&self, record: &JobRecord) -> Result<bool, Error> {
+        let result = sqlx::query(&format!(
+            "INSERT INTO job_logs ({JOB_RECORD_COLUMNS}) SELECT $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14 WHERE NOT EXISTS (SELECT 1 FROM job_logs WHERE uuid = $6)"
+        ))
+        .bind(record.start_time)
+        .bind(record.end_time)
+        .bind(record.exit_code)
+        .bind(&record.job_id)
+        .bind(&record.revision)
+        .bind(record.uuid)
+        .bind(&record.log_path)
+        .bind(&record.status)
+        .bind(&record.reason)
+        .bind(&record.package_version)
+        .bind(&record.image_digest)
+        .bind(record.attempt_group)
+        .bind(record.attempt)
+        .bind(record.run_uuid)
+        .execute(&self.pool)
+        .await?;
+        return Ok(result.rows_affected() > 0);
+    }
+
+    async fn import_package_version(&self, version: &PackageVersion) -> Result<bool, Error> {
+        let result = sqlx::query("INSERT INTO package_versions (package, version, checked_at, uuid) SELECT $1, $2, $3, $4 WHERE NOT EXISTS (SELECT 1 FROM package_versions WHERE uuid = $4)")
+            .bind(&version.package)
+            .bind(&version.version)
+            .bind(version.checked_at)
+            .bind(version.uuid)
+            .execute(&self.pool)
+            .await?;
+        return Ok(result.rows_affected() > 0);
+    }
+}
+
+/// Returns the options for connecting to postgres, from the `database` section of the config, with the `GREGORY_DB_*` environment variables (from `vars`) taking precedence over it
+///
+/// Either a URL, or at least a host and user, have to be set; anything set separately overrides what's in the URL
+pub(crate) fn connect_options(
+    database: &Database,
+    vars: &HashMap<String, String>,
+) -> Result<PgConnectOptions, Error> {
+    let setting = |var: &str, value: &Option<String>| -> Option<String> {
+        return vars.get(var).cloned().or(value.clone());
+    };
+
+    let url = setting("GREGORY_DB_URL", &database.url);
+    let mut host = setting("GREGORY_DB_ADDRESS", &database.host);
+    // `GREGORY_DB_ADDRESS` used to be put straight into the URL, so it might have a port on the end
+    let mut address_port: Option<u16> = None;
+    if let Some((address, port)) = host.clone().and_then(|host| {
+        let (address, port) = host.rsplit_once(':')?;
+        return Some((address.to_string(), port.parse::<u16>().ok()?));
+    }) {
+        if !address.contains(':') {
+            host = Some(address);
+            address_port = Some(port);
+        }
+    }
+    let port = match vars.get("GREGORY_DB_PORT") {
+        Some(port) => Some(port.parse::<u16>().map_err(|_| {
+            Error::DatabaseConfig(format!("`GREGORY_DB_PORT` isn't a valid port: {port}"))
+        })?),
+        None => address_port.or(database.port),
+    };
+    let user = setting("GREGORY_DB_USER", &database.user);
+
+    let mut options = match &url {
+        Some(url) => PgConnectOptions::from_str(url)
+            .map_err(|e| Error::DatabaseConfig(format!("invalid database URL: {e}")))?,
+        None => {
+            if host.is_none() {
+                return Err(Error::DatabaseConfig("no database host set; set `url` or `host` in the `database` section of the config, or `GREGORY_DB_URL` or `GREGORY_DB_ADDRESS`".to_string()));
+            }
+            if user.is_none() {
+                return Err(Error::DatabaseConfig("no database user set; set `url` or `user` in the `database` section of the config, or `GREGORY_DB_URL` or `GREGORY_DB_USER`".to_string()));
+            }
+            PgConnectOptions::new_without_pgpass().database("gregory")
+        }
+    };
+
+    // a host starting with `/` is the directory with postgres' unix socket in it
+    if let Some(host) = &host {
+        options = options.host(host);
+    }
+    if let Some(port) = port {
+        options = options.port(port);
+    }
+    if let Some(user) = &user {
+        options = options.username(user);
+    }
+    if let Some(password) = vars.get("GREGORY_DB_PASSWORD") {
+        options = options.password(password);
+    } else if let Some(password_file) = setting("GREGORY_DB_PASSWORD_FILE", &database.password_file)
+    {
+        let password = fs::read_to_string(&password_file).map_err(|e| {
+            Error::DatabaseConfig(format!("couldn't read password file {password_file}: {e}"))
+        })?;
+        options = options.password(password.trim_end_matches(['\r', '\n']));
+    }
+    if let Some(dbname) = setting("GREGORY_DB_NAME", &database.dbname) {
+        options = options.database(&dbname);
+    }
+    if let Some(sslmode) = setting("GREGORY_DB_SSLMODE", &database.sslmode) {
+        options = options.ssl_mode(
+            PgSslMode::from_str(&sslmode)
+                .map_err(|_| Error::DatabaseConfig(format!("unknown sslmode: {sslmode}")))?,
+        );
+    }
+
+    return Ok(options);
+}
+
+#[test]
+pub(crate) fn test_connect_options() {
+    let database: Database = toml::from_str(
+        r#"
+        host = "db.example.com"
+        port = 5433
+        user = "gregory"
+        dbname = "builds"
+        sslmode = "require"
+        "#,
+    )
+    .unwrap();
+
+    let options = connect_options(&database, &HashMap::new()).unwrap();
+    assert_eq!(options.get_host(), "db.example.com");
+    assert_eq!(options.get_port(), 5433);
+    assert_eq!(options.get_username(), "gregory");
+    assert_eq!(options.get_database(), Some("builds"));
+    assert!(matches!(options.get_ssl_mode(), PgSslMode::Require));
+
+    // environment variables take precedence, and the address can have a port
+    let vars = HashMap::from([
+        (
+            "GREGORY_DB_ADDRESS".to_string(),
+            "postgres:5434".to_string(),
+        ),
+        ("GREGORY_DB_USER".to_string(), "someone".to_string()),
+    ]);
+    let options = connect_options(&database, &vars).unwrap();
+    assert_eq!(options.get_host(), "postgres");
+    assert_eq!(options.get_port(), 5434);
+    assert_eq!(options.get_username(), "someone");
+
+    let database: Database =
+        toml::from_str(r#"url = "postgres://u:p@localhost:1234/x?sslmode=disable""#).unwrap();
+    let options = connect_options(&database, &HashMap::new()).unwrap();
+    assert_eq!(options.get_host(), "localhost");
+    assert_eq!(options.get_port(), 1234);
+    assert_eq!(options.get_database(), Some("x"));
+    assert!(matches!(options.get_ssl_mode(), PgSslMode::Disable));
+
+    // the old default database name is kept
+    let vars = HashMap::from([
+        (
+            "GREGORY_DB_ADDRESS".to_string(),
+            "/run/postgresql".to_string(),
+        ),
+        ("GREGORY_DB_USER".to_string(), "gregory".to_string()),
+    ]);
+    let options = connect_options(&Database::default(), &vars).unwrap();
+    assert_eq!(options.get_database(), Some("gregory"));
+
+    assert!(matches!(
+        connect_options(&Database::default(), &HashMap::new()),
+        Err(Error::DatabaseConfig(_))
+    ));
+}
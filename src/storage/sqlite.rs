@@ -0,0 +1,487 @@
+//! The SQLite backend, for when running postgres isn't worth it
+//!
+//! Times are stored as RFC 3339 text, and uuids as hyphenated text, so they're readable with the `sqlite3` CLI
+
+use super::{
+    JobRecord, JobStart, OutputLine, PackageVersion, RunRecord, Storage, JOB_RECORD_COLUMNS,
+    RUN_RECORD_COLUMNS,
+};
+use crate::data::JobStatus;
+use crate::errors::Error;
+use crate::migrations::{self, Migration};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+use sqlx::{Executor, FromRow, QueryBuilder, Sqlite, SqliteConnection};
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use uuid::fmt::Hyphenated;
+use uuid::Uuid;
+
+/// Stores everything in an SQLite database file
+pub(crate) struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if it doesn't exist
+    pub(crate) async fn open(path: &str) -> Result<SqliteStorage, Error> {
+        if let Some(dir) = Path::new(path).parent() {
+            create_dir_all(dir)?;
+        }
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            // lets output be written while other stuff is being read
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(30));
+        return Ok(SqliteStorage {
+            pool: SqlitePoolOptions::new().connect_with(options).await?,
+        });
+    }
+}
+
+/// A row from `job_logs`, as SQLite stores it - see [`JobRecord`]
+#[derive(FromRow)]
+struct SqliteJobRecord {
+    start_time: Option<DateTime<Utc>>,
+    end_time: Option<DateTime<Utc>>,
+    exit_code: Option<i16>,
+    job_id: String,
+    revision: String,
+    uuid: Hyphenated,
+    log_path: Option<String>,
+    status: Option<String>,
+    reason: Option<String>,
+    package_version: Option<String>,
+    image_digest: Option<String>,
+    attempt_group: Option<Hyphenated>,
+    attempt: Option<i16>,
+    run_uuid: Option<Hyphenated>,
+}
+
+/// A row from `runs`, as SQLite stores it - see [`RunRecord`]
+#[derive(FromRow)]
+struct SqliteRunRecord {
+    uuid: Hyphenated,
+    start_time: DateTime<Utc>,
+    end_time: Option<DateTime<Utc>>,
+    config_hash: String,
+    trigger: String,
+    status: String,
+    succeeded: Option<i32>,
+    failed: Option<i32>,
+    timed_out: Option<i32>,
+    skipped: Option<i32>,
+    cancelled: Option<i32>,
+    not_run: Option<i32>,
+    resumed_from: Option<Hyphenated>,
+}
+
+impl From<SqliteRunRecord> for RunRecord {
+    fn from(run: SqliteRunRecord) -> RunRecord {
+        return RunRecord {
+            uuid: run.uuid.into_uuid(),
+            start_time: run.start_time,
+            end_time: run.end_time,
+            config_hash: run.config_hash,
+            trigger: run.trigger,
+            status: run.status,
+            succeeded: run.succeeded,
+            failed: run.failed,
+            timed_out: run.timed_out,
+            skipped: run.skipped,
+            cancelled: run.cancelled,
+            not_run: run.not_run,
+            resumed_from: run.resumed_from.map(Hyphenated::into_uuid),
+        };
+    }
+}
+
+impl From<SqliteJobRecord> for JobRecord {
+    fn from(record: SqliteJobRecord) -> JobRecord {
+        return JobRecord {
+            start_time: record.start_time,
+            end_time: record.end_time,
+            exit_code: record.exit_code,
+            job_id: record.job_id,
+            revision: record.revision,
+            uuid: record.uuid.into_uuid(),
+            log_path: record.log_path,
+            status: record.status,
+            reason: record.reason,
+            package_version: record.package_version,
+            image_digest: record.image_digest,
+            attempt_group: record.attempt_group.map(|uuid| uuid.into_uuid()),
+            attempt: record.attempt,
+            run_uuid: record.run_uuid.map(|uuid| uuid.into_uuid()),
+        };
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    fn migrations(&self) -> &'static [Migration] {
+        return migrations::SQLITE;
+    }
+
+    async fn migrate(&self) -> Result<Vec<&'static Migration>, Error> {
+        let mut conn = self.pool.acquire().await?;
+        // `IMMEDIATE` takes the write lock straight away, so two runs starting at once (e.g. in daemon mode) don't both try to migrate it
+        conn.execute("BEGIN IMMEDIATE").await?;
+        let applied = migrate_in_transaction(&mut conn).await;
+        match applied {
+            Ok(_) => conn.execute("COMMIT").await?,
+            Err(_) => conn.execute("ROLLBACK").await?,
+        };
+        return applied;
+    }
+
+    async fn applied_migrations(&self) -> Result<HashMap<i32, DateTime<Utc>>, Error> {
+        let exists: bool = sqlx::query_scalar(
+            "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if !exists {
+            return Ok(HashMap::new());
+        }
+
+        let applied: Vec<(i32, DateTime<Utc>)> =
+            sqlx::query_as("SELECT version, applied_at FROM schema_version")
+                .fetch_all(&self.pool)
+                .await?;
+        return Ok(applied.into_iter().collect());
+    }
+
+    async fn job_started(&self, job: &JobStart) -> Result<(), Error> {
+        sqlx::query("INSERT INTO job_logs (start_time, job_id, revision, uuid, attempt_group, attempt, log_path, status, image_digest, run_uuid) VALUES ($1, $2, $3, $4, $5, $6, $7, 'running', $8, $9)")
+            .bind(DateTime::<Utc>::from(job.start_time))
+            .bind(&job.job_id)
+            .bind(&job.revision)
+            .bind(job.uuid.hyphenated())
+            .bind(job.attempt_group.hyphenated())
+            .bind(job.attempt)
+            .bind(&job.log_path)
+            .bind(&job.image_digest)
+            .bind(job.run_uuid.hyphenated())
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn log_output(&self, lines: &[OutputLine]) -> Result<(), Error> {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO job_output (uuid, line_number, stream, time, text) ",
+        );
+        query.push_values(lines, |mut row, line| {
+            row.push_bind(line.uuid.hyphenated())
+                .push_bind(line.line_number)
+                .push_bind(&line.stream)
+                .push_bind(line.time)
+                .push_bind(&line.text);
+        });
+        query.build().execute(&self.pool).await?;
+        return Ok(());
+    }
+
+    async fn finish_job(
+        &self,
+        uuid: &Uuid,
+        end_time: SystemTime,
+        exit_code: Option<i32>,
+        status: &JobStatus,
+        package_version: Option<String>,
+    ) -> Result<(), Error> {
+        sqlx::query("UPDATE job_logs SET end_time = $1, exit_code = $2, status = $3, package_version = $4 WHERE uuid = $5")
+            .bind(DateTime::<Utc>::from(end_time))
+            .bind(exit_code)
+            .bind(status.name())
+            .bind(package_version)
+            .bind(uuid.hyphenated())
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn log_unstarted_job(
+        &self,
+        run_uuid: &Uuid,
+        job_id: &str,
+        revision: &str,
+        status: &JobStatus,
+    ) -> Result<(), Error> {
+        sqlx::query("INSERT INTO job_logs (job_id, revision, uuid, status, reason, run_uuid) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(job_id)
+            .bind(revision)
+            .bind(Uuid::now_v7().hyphenated())
+            .bind(status.name())
+            .bind(status.reason())
+            .bind(run_uuid.hyphenated())
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn job_output(&self, uuid: &Uuid, stream: &str) -> Result<Vec<String>, Error> {
+        let lines: Vec<String> = sqlx::query_scalar(
+            "SELECT text FROM job_output WHERE uuid = $1 AND stream = $2 ORDER BY line_number",
+        )
+        .bind(uuid.hyphenated())
+        .bind(stream)
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(lines);
+    }
+
+    async fn log_package_version(
+        &self,
+        package: &str,
+        version: &str,
+        uuid: &Uuid,
+    ) -> Result<(), Error> {
+        sqlx::query("INSERT INTO package_versions (package, version, checked_at, uuid) VALUES ($1, $2, $3, $4)")
+            .bind(package)
+            .bind(version)
+            .bind(Utc::now())
+            .bind(uuid.hyphenated())
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn last_package_version(&self, package: &str) -> Result<Option<String>, Error> {
+        let version: Option<String> = sqlx::query_scalar(
+            "SELECT version FROM package_versions WHERE package = $1 ORDER BY julianday(checked_at) DESC LIMIT 1",
+        )
+        .bind(package)
+        .fetch_optional(&self.pool)
+        .await?;
+        return Ok(version);
+    }
+
+    async fn last_successful_run(&self, job_id: &str) -> Result<Option<JobRecord>, Error> {
+        let record: Option<SqliteJobRecord> = sqlx::query_as(&format!(
+            "SELECT {JOB_RECORD_COLUMNS} FROM job_logs WHERE job_id = $1 AND status = 'succeeded' ORDER BY julianday(start_time) DESC LIMIT 1"
+        ))
+        .bind(job_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        return Ok(record.map(JobRecord::from));
+    }
+
+    async fn latest_jobs(&self) -> Result<Vec<JobRecord>, Error> {
+        let records: Vec<SqliteJobRecord> = sqlx::query_as(&format!(
+            "SELECT {JOB_RECORD_COLUMNS} FROM job_logs WHERE uuid IN (SELECT max(uuid) FROM job_logs GROUP BY job_id) ORDER BY job_id"
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(records.into_iter().map(JobRecord::from).collect());
+    }
+
+    async fn job_history(&self, job_id: &str, limit: i64) -> Result<Vec<JobRecord>, Error> {
+        let records: Vec<SqliteJobRecord> = sqlx::query_as(&format!(
+            "SELECT {JOB_RECORD_COLUMNS} FROM job_logs WHERE job_id = $1 ORDER BY uuid DESC LIMIT $2"
+        ))
+        .bind(job_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(records.into_iter().map(JobRecord::from).collect());
+    }
+
+    async fn find_job(
+        &self,
+        job_id: &str,
+        run: Option<&Uuid>,
+        revision: Option<&str>,
+    ) -> Result<Option<JobRecord>, Error> {
+        let record: Option<SqliteJobRecord> = sqlx::query_as(&format!(
+            "SELECT {JOB_RECORD_COLUMNS} FROM job_logs WHERE job_id = $1 AND start_time IS NOT NULL AND ($2 IS NULL OR uuid = $2 OR run_uuid = $2) AND ($3 IS NULL OR revision = $3) ORDER BY uuid DESC LIMIT 1"
+        ))
+        .bind(job_id)
+        .bind(run.map(|run| run.hyphenated()))
+        .bind(revision)
+        .fetch_optional(&self.pool)
+        .await?;
+        return Ok(record.map(JobRecord::from));
+    }
+
+    async fn job_output_lines(&self, uuid: &Uuid, after: i32) -> Result<Vec<OutputLine>, Error> {
+        let lines: Vec<(i32, String, DateTime<Utc>, String)> = sqlx::query_as(
+            "SELECT line_number, stream, time, text FROM job_output WHERE uuid = $1 AND line_number > $2 ORDER BY line_number",
+        )
+        .bind(uuid.hyphenated())
+        .bind(after)
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(lines
+            .into_iter()
+            .map(|(line_number, stream, time, text)| OutputLine {
+                uuid: *uuid,
+                line_number,
+                stream,
+                time,
+                text,
+            })
+            .collect());
+    }
+
+    async fn run_started(&self, run: &RunRecord) -> Result<(), Error> {
+        sqlx::query("INSERT INTO runs (uuid, start_time, config_hash, trigger, status, resumed_from) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(run.uuid.hyphenated())
+            .bind(run.start_time)
+            .bind(&run.config_hash)
+            .bind(&run.trigger)
+            .bind(&run.status)
+            .bind(run.resumed_from.map(|uuid| uuid.hyphenated()))
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn finish_run(&self, run: &RunRecord) -> Result<(), Error> {
+        sqlx::query("UPDATE runs SET end_time = $1, status = $2, succeeded = $3, failed = $4, timed_out = $5, skipped = $6, cancelled = $7, not_run = $8 WHERE uuid = $9")
+            .bind(run.end_time)
+            .bind(&run.status)
+            .bind(run.succeeded)
+            .bind(run.failed)
+            .bind(run.timed_out)
+            .bind(run.skipped)
+            .bind(run.cancelled)
+            .bind(run.not_run)
+            .bind(run.uuid.hyphenated())
+            .execute(&self.pool)
+            .await?;
+        return Ok(());
+    }
+
+    async fn runs(&self, limit: i64) -> Result<Vec<RunRecord>, Error> {
+        let runs: Vec<SqliteRunRecord> = sqlx::query_as(&format!(
+            "SELECT {RUN_RECORD_COLUMNS} FROM runs ORDER BY julianday(start_time) DESC LIMIT $1"
+        ))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(runs.into_iter().map(RunRecord::from).collect());
+    }
+
+    async fn find_run(&self, uuid: &Uuid) -> Result<Option<RunRecord>, Error> {
+        let run: Option<SqliteRunRecord> = sqlx::query_as(&format!(
+            "SELECT {RUN_RECORD_COLUMNS} FROM runs WHERE uuid = $1"
+        ))
+        .bind(uuid.hyphenated())
+        .fetch_optional(&self.pool)
+        .await?;
+        return Ok(run.map(RunRecord::from));
+    }
+
+    async fn jobs_in_run(&self, run_uuid: &Uuid) -> Result<Vec<JobRecord>, Error> {
+        let records: Vec<SqliteJobRecord> = sqlx::query_as(&format!(
+            "SELECT {JOB_RECORD_COLUMNS} FROM job_logs WHERE run_uuid = $1 ORDER BY uuid"
+        ))
+        .bind(run_uuid.hyphenated())
+        .fetch_all(&self.pool)
+        .await?;
+        return Ok(records.into_iter().map(JobRecord::from).collect());
+    }
+
+    async fn import_run(&self, run: &RunRecord) -> Result<bool, Error> {
+        let result = sqlx::query(&format!(
+            "INSERT INTO runs ({RUN_RECORD_COLUMNS}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) ON CONFLICT (uuid) DO NOTHING"
+        ))
+        .bind(run.uuid.hyphenated())
+        .bind(run.start_time)
+        .bind(run.end_time)
+        .bind(&run.config_hash)
+        .bind(&run.trigger)
+        .bind(&run.status)
+        .bind(run.succeeded)
+        .bind(run.failed)
+        .bind(run.timed_out)
+        .bind(run.skipped)
+        .bind(run.cancelled)
+        .bind(run.not_run)
+        .bind(run.resumed_from.map(|uuid| uuid.hyphenated()))
+        .execute(&self.pool)
+        .await?;
+        return Ok(result.rows_affected() > 0);
+    }
+
+    async fn import_job(&self, record: &JobRecord) -> Result<bool, Error> {
+        let result = sqlx::query(&format!(
+            "INSERT INTO job_logs ({JOB_RECORD_COLUMNS}) SELECT $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14 WHERE NOT EXISTS (SELECT 1 FROM job_logs WHERE uuid = $6)"
+        ))
+        .bind(record.start_time)
+        .bind(record.end_time)
+        .bind(record.exit_code)
+        .bind(&record.job_id)
+        .bind(&record.revision)
+        .bind(record.uuid.hyphenated())
+        .bind(&record.log_path)
+        .bind(&record.status)
+        .bind(&record.reason)
+        .bind(&record.package_version)
+        .bind(&record.image_digest)
+        .bind(record.attempt_group.map(|uuid| uuid.hyphenated()))
+        .bind(record.attempt)
+        .bind(record.run_uuid.map(|uuid| uuid.hyphenated()))
+        .execute(&self.pool)
+        .await?;
+        return Ok(result.rows_affected() > 0);
+    }
+
+    async fn import_package_version(&self, version: &PackageVersion) -> Result<bool, Error> {
+        let result = sqlx::query("INSERT INTO package_versions (package, version, checked_at, uuid) SELECT $1, $2, $3, $4 WHERE NOT EXISTS (SELECT 1 FROM package_versions WHERE uuid = $4)")
+            .bind(&version.package)
+            .bind(&version.version)
+            .bind(version.checked_at)
+            .bind(version.uuid.hyphenated())
+            .execute(&self.pool)
+            .await?;
+        return Ok(result.rows_affected() > 0);
+    }
+}
+
+/// Applies the pending migrations - see [`SqliteStorage::migrate`], which handles the transaction
+async fn migrate_in_transaction(
+    conn: &mut SqliteConnection,
+) -> Result<Vec<&'static Migration>, Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+    version     integer PRIMARY KEY,
+    description text,
+    applied_at  text
+);
+",
+    )
+    .await?;
+
+    let version: i32 = sqlx::query_scalar("SELECT coalesce(max(version), 0) FROM schema_version")
+        .fetch_one(&mut *conn)
+        .await?;
+    let latest_version = migrations::latest_version(migrations::SQLITE);
+    if version > latest_version {
+        return Err(Error::SchemaTooNew(version, latest_version));
+    }
+
+    let mut applied: Vec<&'static Migration> = Vec::new();
+    for migration in migrations::SQLITE {
+        if migration.version <= version {
+            continue;
+        }
+        conn.execute(migration.sql).await?;
+        sqlx::query(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES ($1, $2, $3)",
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(Utc::now())
+        .execute(&mut *conn)
+        .await?;
+        applied.push(migration);
+    }
+    return Ok(applied);
+}
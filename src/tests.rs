@@ -1,5 +1,37 @@
 #[cfg(test)]
+use crate::{
+    cli::{DbCommands, JobSelection},
+    daemon::parse_schedule,
+    data::{Config, DatabaseBackend, Hook, JobStatus},
+    db,
+    errors::Error,
+    executor::{fits, update_repo_skip_reason, RunOptions, Trigger},
+    logging::{parse_log_line, read_stream, JobLogger},
+    migrations, plan,
+    queries::{logs, optional_duration},
+    run, run_job,
+    runtime::{self, JobSpec},
+    scheduler::JobGraph,
+    shutdown::Cancellation,
+    storage::{
+        self, JobStart, JsonLinesStorage, OutputLine, OutputWriter, RunRecord, SqliteStorage,
+        Storage,
+    },
+    validate::{self, Severity},
+    State,
+};
+#[cfg(test)]
 use alphanumeric_sort::sort_str_slice;
+#[cfg(test)]
+use chrono::Utc;
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::time::Instant;
+#[cfg(test)]
+use std::time::SystemTime;
+#[cfg(test)]
+use uuid::Uuid;
 
 // FIXME: config entries are returned in a random order, so disabling this test for now
 /*
@@ -80,3 +112,1694 @@ fn test_git_versioning() {
         ]
     );
 }
+
+#[tokio::test]
+async fn test_job_order() {
+    let state = State::from_file("gregory.example.toml".to_string())
+        .await
+        .unwrap();
+    let graph = JobGraph::new(&state.dependency_map).unwrap();
+    assert_eq!(
+        graph.order().unwrap(),
+        [
+            "packages.librewolf.version-check",
+            "packages.some-librewolf-dependency.compilation",
+            "packages.some-librewolf-dependency.packaging.fedora",
+            "packages.librewolf.compilation",
+            "packages.librewolf.packaging.fedora",
+            "update-repo.fedora"
+        ]
+    );
+    assert!(graph
+        .dependencies("update-repo.fedora")
+        .contains("packages.some-librewolf-dependency.packaging.fedora"));
+}
+
+#[tokio::test]
+async fn test_dependency_cycle() {
+    let conf: Config = toml::from_str(
+        r#"
+        [packages.a]
+        dependencies = ["b"]
+          [packages.a.compilation]
+          image = "docker.io/library/debian"
+          commands = ["true"]
+          [packages.a.packaging]
+
+        [packages.b]
+        dependencies = ["a"]
+          [packages.b.compilation]
+          image = "docker.io/library/debian"
+          commands = ["true"]
+          [packages.b.packaging]
+
+        [update-repo]
+        "#,
+    )
+    .unwrap();
+
+    match State::from_config(conf).await {
+        Err(Error::DependencyCycle(cycle)) => {
+            assert_eq!(cycle.first(), cycle.last());
+            assert_eq!(cycle.len(), 3);
+        }
+        _ => panic!("expected a dependency cycle"),
+    }
+}
+
+#[test]
+fn test_fits() {
+    let conf: Config = toml::from_str(
+        r#"
+        max-threads = 4
+
+        [packages.big]
+          [packages.big.compilation]
+          threads = 16
+          image = "docker.io/library/debian"
+          commands = ["true"]
+          [packages.big.packaging]
+
+        [update-repo]
+        "#,
+    )
+    .unwrap();
+    let big = conf.packages["big"].compilation.as_ref().unwrap();
+    assert_eq!(big.capped_threads(conf.max_threads), 4.0);
+
+    // anything fits when nothing's running, so a job that's been capped to the whole budget still gets to run
+    let mut running: HashMap<String, f32> = HashMap::new();
+    assert!(fits(&running, big.capped_threads(conf.max_threads), 4.0));
+    running.insert("a".to_string(), 3.0);
+    assert!(fits(&running, 1.0, 4.0));
+    assert!(!fits(&running, 2.0, 4.0));
+    running.insert("b".to_string(), 1.0);
+    assert!(!fits(&running, 0.5, 4.0));
+}
+
+#[tokio::test]
+async fn test_dependents() {
+    let conf: Config = toml::from_str(
+        r#"
+        [packages.a]
+          [packages.a.compilation]
+          image = "docker.io/library/debian"
+          commands = ["true"]
+          [packages.a.packaging.fedora]
+          image = "docker.io/library/fedora"
+          commands = ["true"]
+
+        [packages.b]
+        dependencies = ["a"]
+          [packages.b.compilation]
+          image = "docker.io/library/debian"
+          commands = ["true"]
+          [packages.b.packaging.fedora]
+          image = "docker.io/library/fedora"
+          commands = ["true"]
+
+        [packages.c]
+          [packages.c.compilation]
+          image = "docker.io/library/debian"
+          commands = ["true"]
+          [packages.c.packaging.fedora]
+          image = "docker.io/library/fedora"
+          commands = ["true"]
+
+        [update-repo]
+        "#,
+    )
+    .unwrap();
+    let state = State::from_config(conf).await.unwrap();
+    let graph = JobGraph::new(&state.dependency_map).unwrap();
+
+    // everything downstream of it gets skipped if it fails, including other packages' jobs
+    let mut dependents: Vec<String> = graph
+        .dependents("packages.a.compilation")
+        .into_iter()
+        .collect();
+    dependents.sort();
+    assert_eq!(
+        dependents,
+        [
+            "packages.a.packaging.fedora",
+            "packages.b.compilation",
+            "packages.b.packaging.fedora"
+        ]
+    );
+    assert!(graph.dependents("packages.c.packaging.fedora").is_empty());
+}
+
+#[tokio::test]
+async fn test_update_repo_skip_reason() {
+    let conf: Config = toml::from_str(
+        r#"
+        [packages.a]
+          [packages.a.compilation]
+          image = "docker.io/library/debian"
+          commands = ["true"]
+          [packages.a.packaging.fedora]
+          image = "docker.io/library/fedora"
+          commands = ["true"]
+
+        [packages.b]
+          [packages.b.compilation]
+          image = "docker.io/library/debian"
+          commands = ["true"]
+          [packages.b.packaging.fedora]
+          image = "docker.io/library/fedora"
+          commands = ["true"]
+
+        [update-repo.fedora]
+        image = "docker.io/library/fedora"
+        commands = ["true"]
+        "#,
+    )
+    .unwrap();
+    let state = State::from_config(conf).await.unwrap();
+    let graph = JobGraph::new(&state.dependency_map).unwrap();
+    let skip_reason = |statuses: &[(&str, JobStatus)]| -> Option<String> {
+        let statuses: HashMap<String, JobStatus> = statuses
+            .iter()
+            .map(|(job_id, status)| (job_id.to_string(), status.clone()))
+            .collect();
+        return update_repo_skip_reason("update-repo.fedora", &graph, &statuses);
+    };
+
+    // it waits for all the packaging jobs, and runs if any of them succeeded
+    assert!(graph
+        .dependencies("update-repo.fedora")
+        .contains("packages.b.packaging.fedora"));
+    assert_eq!(
+        skip_reason(&[
+            ("packages.a.packaging.fedora", JobStatus::Failed),
+            ("packages.b.packaging.fedora", JobStatus::Succeeded),
+        ]),
+        None
+    );
+    assert_eq!(
+        skip_reason(&[
+            ("packages.a.packaging.fedora", JobStatus::Failed),
+            (
+                "packages.b.packaging.fedora",
+                JobStatus::Skipped("dependency packages.b.compilation failed".to_string())
+            ),
+        ]),
+        Some("none of the packaging jobs for fedora succeeded".to_string())
+    );
+    assert_eq!(
+        skip_reason(&[(
+            "packages.a.packaging.fedora",
+            JobStatus::Skipped("dependency packages.a.compilation failed".to_string())
+        )]),
+        Some("none of the packaging jobs for fedora ran".to_string())
+    );
+    assert_eq!(
+        update_repo_skip_reason("packages.a.compilation", &graph, &HashMap::new()),
+        None
+    );
+}
+
+#[test]
+fn test_read_stream() {
+    let data_dir = std::env::temp_dir()
+        .join(format!("gregory-test-{}", Uuid::now_v7()))
+        .display()
+        .to_string();
+    let mut logger = JobLogger::new(
+        data_dir.clone(),
+        "packages.a.version-check".to_string(),
+        "1".to_string(),
+        Uuid::now_v7(),
+        true,
+        None,
+    );
+    let start_time = Instant::now();
+    logger.stdout("1.2.3".to_string(), start_time).unwrap();
+    logger.stderr("oops".to_string(), start_time).unwrap();
+    logger.stdout("".to_string(), start_time).unwrap();
+    logger
+        .stdout("[1.000] [stderr] not really".to_string(), start_time)
+        .unwrap();
+
+    // what the version check printed is read back without the timestamps and markers
+    let log_path = logger.path().unwrap();
+    assert_eq!(
+        read_stream(&log_path, "stdout").unwrap(),
+        ["1.2.3", "", "[1.000] [stderr] not really"]
+    );
+    assert_eq!(read_stream(&log_path, "stderr").unwrap(), ["oops"]);
+    assert_eq!(
+        parse_log_line("[0.5] [stdout] a ] [stderr] b"),
+        Some(("stdout", "a ] [stderr] b"))
+    );
+    assert_eq!(parse_log_line("not a log line"), None);
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+/// Writes a config that runs everything on the host, with its database in a new data dir, followed by `body` (where `{data_dir}` is replaced with the data dir); returns the data dir and the path to the config
+#[cfg(test)]
+fn host_run_config(body: &str) -> (String, String) {
+    let data_dir = std::env::temp_dir()
+        .join(format!("gregory-test-{}", Uuid::now_v7()))
+        .display()
+        .to_string();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let config_path = format!("{data_dir}/gregory.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            r#"
+            data-dir = "{data_dir}"
+            runtime = "host"
+            log-files = false
+            {}
+
+            [database]
+            backend = "sqlite"
+            "#,
+            body.replace("{data_dir}", &data_dir)
+        ),
+    )
+    .unwrap();
+    return (data_dir, config_path);
+}
+
+#[tokio::test]
+async fn test_concurrency() {
+    let threads = HashMap::from([("a", 3.0), ("b", 3.0), ("c", 1.0), ("d", 1.0), ("big", 4.0)]);
+    let mut body = "max-jobs = 2\nmax-threads = 4\n".to_string();
+    for package in ["a", "b", "c", "d", "big"] {
+        // big asks for more than the whole budget, so it's capped to it
+        let job_threads = if package == "big" {
+            16.0
+        } else {
+            threads[package]
+        };
+        body.push_str(&format!(
+            r#"
+            [packages.{package}]
+            packaging = {{}}
+              [packages.{package}.compilation]
+              threads = {job_threads}
+              image = "debian"
+              commands = ["echo + {package} >> {{data_dir}}/jobs", "sleep 1", "echo - {package} >> {{data_dir}}/jobs"]
+            "#
+        ));
+    }
+    body.push_str("[update-repo]\n");
+    let (data_dir, config_path) = host_run_config(&body);
+
+    let statuses = run(config_path, RunOptions::default()).await.unwrap();
+    assert_eq!(statuses.len(), 5);
+    assert!(statuses
+        .values()
+        .all(|status| *status == JobStatus::Succeeded));
+
+    // go through when each job started and finished, checking what was running at once
+    let mut running: Vec<String> = Vec::new();
+    let mut most_running = 0;
+    for line in std::fs::read_to_string(format!("{data_dir}/jobs"))
+        .unwrap()
+        .lines()
+    {
+        let (event, package) = line.split_once(' ').unwrap();
+        if event == "+" {
+            running.push(package.to_string());
+        } else {
+            running.retain(|running| running != package);
+        }
+        let threads_in_use: f64 = running
+            .iter()
+            .map(|package| threads[package.as_str()])
+            .sum();
+        assert!(
+            running.len() <= 2,
+            "more than max-jobs running: {running:?}"
+        );
+        assert!(
+            threads_in_use <= 4.0,
+            "more than max-threads in use: {running:?}"
+        );
+        most_running = most_running.max(running.len());
+    }
+    assert_eq!(most_running, 2);
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_failure_skips_dependents() {
+    let (data_dir, config_path) = host_run_config(
+        r#"
+        [packages.a]
+          [packages.a.compilation]
+          image = "debian"
+          commands = ["exit 1"]
+          [packages.a.packaging.fedora]
+          image = "debian"
+          commands = ["true"]
+
+        [packages.b]
+        dependencies = ["a"]
+          [packages.b.compilation]
+          image = "debian"
+          commands = ["true"]
+          [packages.b.packaging.fedora]
+          image = "debian"
+          commands = ["true"]
+
+        [packages.c]
+          [packages.c.compilation]
+          image = "debian"
+          commands = ["true"]
+          [packages.c.packaging.fedora]
+          image = "debian"
+          commands = ["true"]
+
+        [update-repo.fedora]
+        image = "debian"
+        commands = ["true"]
+        "#,
+    );
+
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(statuses["packages.a.compilation"], JobStatus::Failed);
+    // everything downstream of it, including other packages' jobs
+    let skipped = JobStatus::Skipped("dependency packages.a.compilation failed".to_string());
+    for job_id in [
+        "packages.a.packaging.fedora",
+        "packages.b.compilation",
+        "packages.b.packaging.fedora",
+    ] {
+        assert_eq!(statuses[job_id], skipped);
+    }
+    // but not anything else
+    assert_eq!(statuses["packages.c.compilation"], JobStatus::Succeeded);
+    assert_eq!(
+        statuses["packages.c.packaging.fedora"],
+        JobStatus::Succeeded
+    );
+
+    let conf = Config::from_file(config_path).unwrap();
+    let storage = storage::start(&conf).await.unwrap();
+    let record = storage
+        .job_history("packages.b.packaging.fedora", 1)
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(record.status.as_deref(), Some("skipped"));
+    assert_eq!(
+        record.reason.as_deref(),
+        Some("dependency packages.a.compilation failed")
+    );
+    assert_eq!(storage.runs(1).await.unwrap()[0].status, "failed");
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_update_repo() {
+    let (data_dir, config_path) = host_run_config(
+        r#"
+        [packages.a]
+          [packages.a.compilation]
+          image = "debian"
+          commands = ["test ! -f {data_dir}/break-compilation"]
+          [packages.a.packaging.fedora]
+          image = "debian"
+          commands = ["echo a >> {data_dir}/order", "exit 1"]
+
+        [packages.b]
+          [packages.b.compilation]
+          image = "debian"
+          commands = ["test ! -f {data_dir}/break-compilation"]
+          [packages.b.packaging.fedora]
+          image = "debian"
+          commands = ["echo b >> {data_dir}/order", "test ! -f {data_dir}/break-b"]
+
+        [update-repo.fedora]
+        image = "debian"
+        commands = ["echo update-repo >> {data_dir}/order"]
+        "#,
+    );
+
+    // one of the packaging jobs succeeding is enough, and it waits for both of them
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(statuses["packages.a.packaging.fedora"], JobStatus::Failed);
+    assert_eq!(statuses["update-repo.fedora"], JobStatus::Succeeded);
+    let order = std::fs::read_to_string(format!("{data_dir}/order")).unwrap();
+    assert_eq!(order.lines().last(), Some("update-repo"));
+    assert_eq!(order.lines().count(), 3);
+
+    // but not if they all failed
+    std::fs::write(format!("{data_dir}/break-b"), "").unwrap();
+    let options = RunOptions {
+        force: true,
+        ..Default::default()
+    };
+    let statuses = run(config_path.clone(), options.clone()).await.unwrap();
+    assert_eq!(
+        statuses["update-repo.fedora"],
+        JobStatus::Skipped("none of the packaging jobs for fedora succeeded".to_string())
+    );
+
+    // or none of them ran
+    std::fs::write(format!("{data_dir}/break-compilation"), "").unwrap();
+    let statuses = run(config_path, options).await.unwrap();
+    assert_eq!(
+        statuses["update-repo.fedora"],
+        JobStatus::Skipped("none of the packaging jobs for fedora ran".to_string())
+    );
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_version_check_skips() {
+    let (data_dir, config_path) = host_run_config(
+        r#"
+        [packages.a]
+        version-check = ["cat {data_dir}/version"]
+          [packages.a.compilation]
+          image = "debian"
+          commands = ["true"]
+          [packages.a.packaging.fedora]
+          image = "debian"
+          commands = ["true"]
+
+        [update-repo.fedora]
+        image = "debian"
+        commands = ["true"]
+        "#,
+    );
+    let version_path = format!("{data_dir}/version");
+
+    std::fs::write(&version_path, "1.0\n").unwrap();
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(statuses["packages.a.compilation"], JobStatus::Succeeded);
+    assert_eq!(
+        statuses["packages.a.packaging.fedora"],
+        JobStatus::Succeeded
+    );
+
+    // the same version was already built
+    let start = std::time::Instant::now();
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    // skipping a job shouldn't hold up the ones after it
+    assert!(start.elapsed().as_secs() < 5);
+    assert_eq!(statuses["packages.a.version-check"], JobStatus::Succeeded);
+    let skipped = JobStatus::Skipped("revision 1 was already built for version 1.0".to_string());
+    assert_eq!(statuses["packages.a.compilation"], skipped);
+    assert_eq!(statuses["packages.a.packaging.fedora"], skipped);
+
+    // but a new one wasn't
+    std::fs::write(&version_path, "1.1\n").unwrap();
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(statuses["packages.a.compilation"], JobStatus::Succeeded);
+    assert_eq!(
+        statuses["packages.a.packaging.fedora"],
+        JobStatus::Succeeded
+    );
+    let conf = Config::from_file(config_path).unwrap();
+    let storage = storage::start(&conf).await.unwrap();
+    assert_eq!(
+        storage.last_package_version("a").await.unwrap().as_deref(),
+        Some("1.1")
+    );
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_package_jobs() {
+    let state = State::from_file("gregory.example.toml".to_string())
+        .await
+        .unwrap();
+    let mut job_ids: Vec<String> = state
+        .package_jobs("some-librewolf-dependency")
+        .unwrap()
+        .into_iter()
+        .collect();
+    job_ids.sort();
+    assert_eq!(
+        job_ids,
+        [
+            "packages.some-librewolf-dependency.compilation",
+            "packages.some-librewolf-dependency.packaging.fedora",
+            "update-repo.fedora"
+        ]
+    );
+    assert!(state.package_jobs("not-a-package").is_err());
+}
+
+#[test]
+fn test_schedules() {
+    assert!(parse_schedule("0 3 * * *").is_ok());
+    assert!(parse_schedule("0 30 2 * * Sun").is_ok());
+    assert!(parse_schedule("every day at 3").is_err());
+}
+
+#[test]
+fn test_hooks_config() {
+    let conf: Config = toml::from_str(
+        r#"
+        [packages]
+        [update-repo]
+
+        [hooks]
+        on-job-failure = ["echo $GREGORY_JOB_ID failed"]
+
+          [[hooks.after-update-repo]]
+          image = "docker.io/library/fedora"
+          commands = ["echo signing"]
+        "#,
+    )
+    .unwrap();
+
+    assert!(matches!(
+        conf.hooks.on_job_failure.as_slice(),
+        [Hook::Command(command)] if command == "echo $GREGORY_JOB_ID failed"
+    ));
+    assert!(matches!(
+        conf.hooks.after_update_repo.as_slice(),
+        [Hook::Job(job)] if job.image == "docker.io/library/fedora"
+    ));
+    assert!(conf.hooks.before_job.is_empty());
+}
+
+#[test]
+fn test_retry_backoff() {
+    let conf: Config = toml::from_str(
+        r#"
+        [packages.librewolf]
+          [packages.librewolf.compilation]
+          image = "docker.io/library/debian"
+          commands = ["./build.sh"]
+          retries = 3
+          retry-delay = 10
+          [packages.librewolf.packaging.fedora]
+          image = "docker.io/library/fedora"
+          commands = ["./package.sh"]
+
+        [update-repo]
+        "#,
+    )
+    .unwrap();
+
+    let compilation = conf.packages["librewolf"].compilation.clone().unwrap();
+    assert_eq!(compilation.retries, 3);
+    let backoffs: Vec<u64> = (1..=3)
+        .map(|attempt| compilation.retry_backoff(attempt).as_secs())
+        .collect();
+    assert_eq!(backoffs, [10, 20, 40]);
+
+    let packaging = &conf.packages["librewolf"].packaging["fedora"];
+    assert_eq!(packaging.retries, 0);
+    assert_eq!(packaging.retry_delay, 30);
+}
+
+/// Returns a config with a single package, `test`, whose compilation runs `commands` on the host, using a new temporary data dir
+#[cfg(test)]
+fn host_config(commands: &[&str]) -> Config {
+    let data_dir = std::env::temp_dir().join(format!("gregory-test-{}", Uuid::now_v7()));
+    let mut conf: Config = toml::from_str(&format!(
+        r#"
+        data-dir = "{}"
+        runtime = "host"
+
+        [packages.test]
+          [packages.test.compilation]
+          image = "docker.io/library/debian"
+          commands = []
+          [packages.test.packaging]
+
+        [update-repo]
+        "#,
+        data_dir.display()
+    ))
+    .unwrap();
+    conf.packages
+        .get_mut("test")
+        .unwrap()
+        .compilation
+        .as_mut()
+        .unwrap()
+        .commands = commands.iter().map(|command| command.to_string()).collect();
+    return conf;
+}
+
+#[test]
+fn test_host_runtime() {
+    let conf = host_config(&["echo hi", "echo $GREETING", "echo oops >&2", "exit 3"]);
+    let job = conf.packages["test"].compilation.clone().unwrap();
+    let env = HashMap::from([("GREETING".to_string(), "hello".to_string())]);
+
+    let job_exit_status = run_job(
+        &conf,
+        "packages.test.compilation".to_string(),
+        job,
+        Uuid::now_v7(),
+        &env,
+        None,
+        &Cancellation::default(),
+    );
+
+    assert_eq!(job_exit_status.exit_code, Some(3));
+    assert_eq!(
+        read_stream(job_exit_status.log_path.as_ref().unwrap(), "stdout").unwrap(),
+        ["hi", "hello"]
+    );
+    assert_eq!(
+        read_stream(job_exit_status.log_path.as_ref().unwrap(), "stderr").unwrap(),
+        ["oops"]
+    );
+    std::fs::remove_dir_all(&conf.data_dir).unwrap();
+}
+
+#[test]
+fn test_timeout() {
+    let mut conf = host_config(&["echo started", "sleep 30", "echo finished"]);
+    conf.timeout = Some(1);
+    let job = conf.packages["test"].compilation.clone().unwrap();
+
+    let start = std::time::Instant::now();
+    let job_exit_status = run_job(
+        &conf,
+        "packages.test.compilation".to_string(),
+        job,
+        Uuid::now_v7(),
+        &HashMap::new(),
+        None,
+        &Cancellation::default(),
+    );
+
+    assert!(job_exit_status.timed_out);
+    assert!(start.elapsed().as_secs() < 15);
+    assert_eq!(
+        JobStatus::from_exit_status(&job_exit_status),
+        JobStatus::TimedOut
+    );
+    assert_eq!(
+        read_stream(job_exit_status.log_path.as_ref().unwrap(), "stdout").unwrap(),
+        ["started"]
+    );
+    std::fs::remove_dir_all(&conf.data_dir).unwrap();
+}
+
+#[test]
+fn test_cancel() {
+    let conf = host_config(&["echo started", "sleep 30", "echo finished"]);
+    let job = conf.packages["test"].compilation.clone().unwrap();
+    let run_id = Uuid::now_v7();
+    let cancellation = Cancellation::default();
+
+    let start = std::time::Instant::now();
+    std::thread::spawn({
+        let cancellation = cancellation.clone();
+        move || {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            cancellation.cancel();
+        }
+    });
+    let job_exit_status = run_job(
+        &conf,
+        "packages.test.compilation".to_string(),
+        job,
+        run_id,
+        &HashMap::new(),
+        None,
+        &cancellation,
+    );
+
+    assert!(job_exit_status.cancelled);
+    assert!(!job_exit_status.timed_out);
+    assert!(start.elapsed().as_secs() < 15);
+    assert_eq!(
+        JobStatus::from_exit_status(&job_exit_status),
+        JobStatus::Cancelled
+    );
+    assert!(!std::path::Path::new(&format!("{}/tmp/{run_id}.sh", conf.data_dir)).exists());
+    std::fs::remove_dir_all(&conf.data_dir).unwrap();
+}
+
+#[test]
+fn test_podman_command() {
+    let conf: Config = toml::from_str(
+        r#"
+        [packages]
+        [update-repo.fedora]
+        image = "docker.io/library/fedora"
+        commands = ["echo hai"]
+        volumes = ["repo", "not-a-volume"]
+
+        [volumes]
+        repo = "./data/repo:/repo"
+        "#,
+    )
+    .unwrap();
+    let job = &conf.update_repo["fedora"];
+    let env = HashMap::from([("A".to_string(), "b".to_string())]);
+    let spec = JobSpec {
+        name: "update-repo.fedora-1234".to_string(),
+        job,
+        script_path: "./data/tmp/1234.sh".to_string(),
+        threads: 2.0,
+        volumes: vec!["./data/repo:/repo".to_string()],
+        env: &env,
+    };
+
+    let cmd = runtime::for_job(&conf, job).command(&spec);
+    assert_eq!(cmd.get_program(), "podman");
+    assert_eq!(
+        cmd.get_args().collect::<Vec<_>>(),
+        [
+            "run",
+            "--rm",
+            "--quiet",
+            "--name=update-repo.fedora-1234",
+            "--cpus=2",
+            "--privileged=false",
+            "--volume=./data/tmp/1234.sh:/gregory-entrypoint.sh",
+            "--volume=./data/repo:/repo",
+            "--env=A=b",
+            "--entrypoint=/bin/sh",
+            "docker.io/library/fedora",
+            "/gregory-entrypoint.sh"
+        ]
+    );
+}
+
+#[test]
+fn test_migrations_ordered() {
+    for migrations in [migrations::POSTGRES, migrations::SQLITE] {
+        for (i, migration) in migrations.iter().enumerate() {
+            assert_eq!(migration.version, i as i32 + 1);
+        }
+        assert_eq!(
+            migrations::latest_version(migrations),
+            migrations.len() as i32
+        );
+    }
+}
+
+#[test]
+fn test_database_config() {
+    let conf: Config = toml::from_str(
+        r#"
+        [packages]
+        [update-repo]
+
+        [database]
+        backend = "sqlite"
+        path = "/var/lib/gregory/gregory.db"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(conf.database.backend, DatabaseBackend::Sqlite);
+    assert_eq!(
+        conf.database.path.as_deref(),
+        Some("/var/lib/gregory/gregory.db")
+    );
+
+    let conf: Config = toml::from_str("[packages]\n[update-repo]").unwrap();
+    assert_eq!(conf.database.backend, DatabaseBackend::Postgres);
+}
+
+#[tokio::test]
+async fn test_sqlite_storage() {
+    let mut conf = host_config(&["echo 1.2.3", "echo oops >&2"]);
+    conf.log_files = false;
+    conf.database.backend = DatabaseBackend::Sqlite;
+    let job_id = "packages.test.compilation".to_string();
+    let job = conf.packages["test"].compilation.clone().unwrap();
+    let run_id = Uuid::now_v7();
+
+    let storage = storage::start(&conf).await.unwrap();
+    assert!(storage.migrate().await.unwrap().is_empty());
+    assert_eq!(
+        storage.schema_version().await.unwrap(),
+        migrations::latest_version(migrations::SQLITE)
+    );
+
+    let mut run = RunRecord::start("hash".to_string(), Trigger::Manual, None);
+    storage.run_started(&run).await.unwrap();
+    let (output, output_task) = OutputWriter::start(storage.clone());
+    output.job_started(JobStart {
+        uuid: run_id,
+        run_uuid: run.uuid,
+        start_time: SystemTime::now(),
+        job_id: job_id.clone(),
+        revision: job.revision.clone(),
+        attempt_group: run_id,
+        attempt: 1,
+        log_path: None,
+        image_digest: Some("host".to_string()),
+    });
+    let job_exit_status = run_job(
+        &conf,
+        job_id.clone(),
+        job,
+        run_id,
+        &HashMap::new(),
+        Some(output.clone()),
+        &Cancellation::default(),
+    );
+    output.flush().await.unwrap();
+    drop(output);
+    output_task.await.unwrap().unwrap();
+    assert!(job_exit_status.log_path.is_none());
+
+    assert!(storage
+        .last_successful_run(&job_id)
+        .await
+        .unwrap()
+        .is_none());
+    storage
+        .finish_job(
+            &run_id,
+            SystemTime::now(),
+            job_exit_status.exit_code,
+            &JobStatus::Succeeded,
+            Some("1.2.3".to_string()),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        storage.job_output(&run_id, "stdout").await.unwrap(),
+        ["1.2.3"]
+    );
+    assert_eq!(
+        storage.job_output(&run_id, "stderr").await.unwrap(),
+        ["oops"]
+    );
+    let last_run = storage.last_successful_run(&job_id).await.unwrap().unwrap();
+    assert_eq!(last_run.uuid, run_id);
+    assert_eq!(last_run.attempt_group, Some(run_id));
+    assert_eq!(last_run.package_version.as_deref(), Some("1.2.3"));
+    assert_eq!(last_run.image_digest.as_deref(), Some("host"));
+    assert!(last_run.end_time.is_some());
+    assert_eq!(last_run.run_uuid, Some(run.uuid));
+
+    storage
+        .log_unstarted_job(
+            &run.uuid,
+            &job_id,
+            &last_run.revision,
+            &JobStatus::Skipped("up to date".to_string()),
+        )
+        .await
+        .unwrap();
+    let latest = storage.latest_jobs().await.unwrap();
+    assert_eq!(latest.len(), 1);
+    assert_eq!(latest[0].status.as_deref(), Some("skipped"));
+    let history = storage.job_history(&job_id, 10).await.unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[1].uuid, run_id);
+    assert!(history[1].duration().is_some());
+    assert_eq!(storage.job_history(&job_id, 1).await.unwrap().len(), 1);
+
+    // skipped jobs don't have any output, so they're passed over
+    for run in [None, Some(&run_id), Some(&run.uuid)] {
+        let found = storage.find_job(&job_id, run, None).await.unwrap();
+        assert_eq!(found.unwrap().uuid, run_id);
+    }
+    assert!(storage
+        .find_job(&job_id, None, Some("nope"))
+        .await
+        .unwrap()
+        .is_none());
+    let lines = storage.job_output_lines(&run_id, 0).await.unwrap();
+    // stdout and stderr are read separately, so either could come first
+    let mut streams: Vec<(&str, &str)> = lines
+        .iter()
+        .map(|line| (line.stream.as_str(), line.text.as_str()))
+        .collect();
+    streams.sort();
+    assert_eq!(streams, [("stderr", "oops"), ("stdout", "1.2.3")]);
+    assert!(lines[0].line_number < lines[1].line_number);
+    assert_eq!(storage.job_output_lines(&run_id, 1).await.unwrap().len(), 1);
+
+    run.finish(&HashMap::from([(job_id.clone(), JobStatus::Succeeded)]));
+    storage.finish_run(&run).await.unwrap();
+    let runs = storage.runs(10).await.unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].status, "succeeded");
+    assert_eq!(runs[0].trigger, "manual");
+    assert_eq!((runs[0].succeeded, runs[0].failed), (Some(1), Some(0)));
+
+    let not_run = JobStatus::NotRun("the run was cancelled".to_string());
+    storage
+        .log_unstarted_job(&run.uuid, "update-repo.fedora", "1", &not_run)
+        .await
+        .unwrap();
+    let record = storage.latest_jobs().await.unwrap().pop().unwrap();
+    assert_eq!(record.job_id, "update-repo.fedora");
+    assert_eq!(record.status.as_deref(), Some("not-run"));
+    assert_eq!(record.reason.as_deref(), Some("the run was cancelled"));
+    run.finish(&HashMap::from([
+        (job_id.clone(), JobStatus::Cancelled),
+        ("update-repo.fedora".to_string(), not_run),
+    ]));
+    storage.finish_run(&run).await.unwrap();
+    let runs = storage.runs(10).await.unwrap();
+    assert_eq!(runs[0].status, "cancelled");
+    assert_eq!((runs[0].cancelled, runs[0].not_run), (Some(1), Some(1)));
+
+    std::fs::remove_dir_all(&conf.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_json_lines_import() {
+    let dir = std::env::temp_dir().join(format!("gregory-test-{}", Uuid::now_v7()));
+    let json_lines = JsonLinesStorage::open(&dir.join("no-db").display().to_string()).unwrap();
+    let job_id = "packages.test.compilation";
+    let run_id = Uuid::now_v7();
+    let run = RunRecord::start("hash".to_string(), Trigger::Manual, None);
+
+    json_lines.run_started(&run).await.unwrap();
+    json_lines
+        .job_started(&JobStart {
+            uuid: run_id,
+            run_uuid: run.uuid,
+            start_time: SystemTime::now(),
+            job_id: job_id.to_string(),
+            revision: "1".to_string(),
+            attempt_group: run_id,
+            attempt: 1,
+            log_path: None,
+            image_digest: None,
+        })
+        .await
+        .unwrap();
+    json_lines
+        .log_output(&[OutputLine {
+            uuid: run_id,
+            line_number: 1,
+            stream: "stdout".to_string(),
+            time: Utc::now(),
+            text: "hi".to_string(),
+        }])
+        .await
+        .unwrap();
+    json_lines
+        .finish_job(
+            &run_id,
+            SystemTime::now(),
+            Some(0),
+            &JobStatus::Succeeded,
+            None,
+        )
+        .await
+        .unwrap();
+    json_lines
+        .log_unstarted_job(
+            &run.uuid,
+            "update-repo.fedora",
+            "1",
+            &JobStatus::Skipped("no reason".to_string()),
+        )
+        .await
+        .unwrap();
+    json_lines
+        .log_package_version("test", "1.0", &run_id)
+        .await
+        .unwrap();
+
+    // the job was written twice, but only the last one counts
+    let records = json_lines.job_records().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].status.as_deref(), Some("succeeded"));
+    assert_eq!(
+        json_lines
+            .last_successful_run(job_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .uuid,
+        run_id
+    );
+    assert_eq!(
+        json_lines
+            .last_package_version("test")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("1.0")
+    );
+
+    let sqlite = SqliteStorage::open(&dir.join("gregory.db").display().to_string())
+        .await
+        .unwrap();
+    sqlite.migrate().await.unwrap();
+    let counts = storage::import(&json_lines, &sqlite).await.unwrap();
+    assert_eq!(
+        (
+            counts.runs,
+            counts.jobs,
+            counts.output_lines,
+            counts.package_versions
+        ),
+        (1, 2, 1, 1)
+    );
+    assert_eq!(sqlite.job_output(&run_id, "stdout").await.unwrap(), ["hi"]);
+    assert_eq!(
+        sqlite
+            .last_successful_run(job_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .exit_code,
+        Some(0)
+    );
+
+    // importing again doesn't duplicate anything
+    let counts = storage::import(&json_lines, &sqlite).await.unwrap();
+    assert_eq!((counts.jobs, counts.existing_jobs), (0, 2));
+    assert_eq!(sqlite.job_output(&run_id, "stdout").await.unwrap(), ["hi"]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_optional_duration() {
+    assert_eq!(optional_duration(None), "-");
+    assert_eq!(optional_duration(Some(4.23)), "4.2s");
+    assert_eq!(optional_duration(Some(723.0)), "12m 3s");
+    assert_eq!(optional_duration(Some(7530.0)), "2h 5m");
+}
+
+#[test]
+fn test_validate() {
+    let example = std::fs::read_to_string("gregory.example.toml").unwrap();
+    assert!(validate::validate(&example).is_empty());
+
+    let source = r#"max-threads = 4
+retires = 3
+
+[packages.a]
+dependencies = ["b", "nope"]
+  [packages.a.compilation]
+  image = "x"
+  threads = 8
+  commands = ["true"]
+  volumes = ["missing", "ok"]
+  [packages.a.packaging.arch]
+  image = "x"
+  commands = ["true"]
+
+[packages.b]
+dependencies = ["a"]
+packaging = {}
+
+[update-repo.fedora]
+image = "x"
+commands = ["true"]
+timeout = "soon"
+
+[[hooks.before-job]]
+image = "x"
+commands = ["true"]
+volume = ["ok"]
+
+[volumes]
+ok = "/a:/b"
+"#;
+    let diagnostics = validate::validate(source);
+    let summary: Vec<(Severity, &str)> = diagnostics
+        .iter()
+        .map(|diagnostic| (diagnostic.severity, diagnostic.message.as_str()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (Severity::Error, "unknown key `retires` in the config"),
+            (
+                Severity::Error,
+                "unknown dependency `nope`; there's no `[packages.nope]`"
+            ),
+            (
+                Severity::Warning,
+                "`threads` is more than `max-threads` (4), so it'll be limited to that"
+            ),
+            (
+                Severity::Error,
+                "unknown volume `missing`; it's not in `[volumes]`"
+            ),
+            (
+                Severity::Warning,
+                "there's no `[update-repo.arch]`, so nothing will update the arch repo after this is packaged"
+            ),
+            (
+                Severity::Error,
+                "dependency cycle detected: packages.a -> packages.b -> packages.a"
+            ),
+            (
+                Severity::Error,
+                "invalid type: string \"soon\", expected u64"
+            ),
+            (Severity::Error, "unknown key `volume` in a `before-job` hook"),
+        ]
+    );
+
+    assert_eq!(
+        validate::format_diagnostic("gregory.toml", source, &diagnostics[1]),
+        r#"error: unknown dependency `nope`; there's no `[packages.nope]`
+ --> gregory.toml:5:22
+  |
+5 | dependencies = ["b", "nope"]
+  |                      ^^^^^^"#
+    );
+}
+
+#[tokio::test]
+async fn test_plan() {
+    let state = State::from_file("gregory.example.toml".to_string())
+        .await
+        .unwrap();
+    let steps = plan::steps(&state, &JobSelection::default()).unwrap();
+    let job_ids: Vec<&str> = steps.iter().map(|step| step.job_id.as_str()).collect();
+    assert_eq!(
+        job_ids,
+        [
+            "packages.librewolf.version-check",
+            "packages.some-librewolf-dependency.compilation",
+            "packages.some-librewolf-dependency.packaging.fedora",
+            "packages.librewolf.compilation",
+            "packages.librewolf.packaging.fedora",
+            "update-repo.fedora",
+        ]
+    );
+
+    // only direct dependencies, even though it has to wait for everything before it
+    assert_eq!(
+        steps[5].dependencies,
+        ["packages.librewolf.packaging.fedora"]
+    );
+    assert_eq!(
+        steps[3].dependencies,
+        [
+            "packages.librewolf.version-check",
+            "packages.some-librewolf-dependency.packaging.fedora"
+        ]
+    );
+
+    let compilation = &steps[3];
+    assert_eq!(compilation.threads, 6.0);
+    assert_eq!(compilation.volumes, ["./data/librewolf:/librewolf"]);
+    assert_eq!(compilation.runtime, "podman");
+    assert_eq!(compilation.command[..3], ["podman", "run", "--rm"]);
+    assert!(compilation
+        .command
+        .contains(&"--name=packages.librewolf.compilation-{run-id}".to_string()));
+    assert!(compilation
+        .command
+        .contains(&"--volume=./data/librewolf:/librewolf".to_string()));
+}
+
+#[tokio::test]
+async fn test_select() {
+    let state = State::from_file("gregory.example.toml".to_string())
+        .await
+        .unwrap();
+    let graph = JobGraph::new(&state.dependency_map).unwrap();
+    let selected = |selection: JobSelection| -> Vec<String> {
+        let mut job_ids = state.select(&graph, &selection).unwrap().order().unwrap();
+        job_ids.sort();
+        return job_ids;
+    };
+
+    assert_eq!(selected(JobSelection::default()).len(), 6);
+    // the version check always comes along
+    assert_eq!(
+        selected(JobSelection {
+            jobs: vec!["packages.librewolf.compilation".to_string()],
+            ..Default::default()
+        }),
+        [
+            "packages.librewolf.compilation",
+            "packages.librewolf.version-check"
+        ]
+    );
+    // and so does update-repo, for packaging
+    assert_eq!(
+        selected(JobSelection {
+            jobs: vec!["packages.librewolf.packaging.fedora".to_string()],
+            ..Default::default()
+        }),
+        [
+            "packages.librewolf.packaging.fedora",
+            "packages.librewolf.version-check",
+            "update-repo.fedora"
+        ]
+    );
+    assert_eq!(
+        selected(JobSelection {
+            distros: vec!["fedora".to_string()],
+            ..Default::default()
+        }),
+        [
+            "packages.librewolf.packaging.fedora",
+            "packages.librewolf.version-check",
+            "packages.some-librewolf-dependency.packaging.fedora",
+            "update-repo.fedora"
+        ]
+    );
+    assert_eq!(
+        selected(JobSelection {
+            jobs: vec!["packages.some-librewolf-dependency.compilation".to_string()],
+            with_dependents: true,
+            ..Default::default()
+        })
+        .len(),
+        6
+    );
+    assert_eq!(
+        selected(JobSelection {
+            jobs: vec!["packages.librewolf.packaging.fedora".to_string()],
+            with_deps: true,
+            ..Default::default()
+        }),
+        [
+            "packages.librewolf.compilation",
+            "packages.librewolf.packaging.fedora",
+            "packages.librewolf.version-check",
+            "packages.some-librewolf-dependency.compilation",
+            "packages.some-librewolf-dependency.packaging.fedora",
+            "update-repo.fedora"
+        ]
+    );
+
+    assert!(matches!(
+        state.select(
+            &graph,
+            &JobSelection {
+                distros: vec!["arch".to_string()],
+                ..Default::default()
+            }
+        ),
+        Err(Error::UnknownDistro(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_resume() {
+    let (data_dir, config_path) = host_run_config(
+        r#"
+        [packages.a]
+        packaging = {}
+          [packages.a.compilation]
+          image = "debian"
+          commands = ["echo a >> {data_dir}/a-runs"]
+
+        [packages.b]
+        dependencies = ["a"]
+        packaging = {}
+          [packages.b.compilation]
+          image = "debian"
+          commands = ["test -f {data_dir}/b-works && echo b >> {data_dir}/b-runs"]
+
+        [packages.c]
+        dependencies = ["b"]
+        packaging = {}
+          [packages.c.compilation]
+          image = "debian"
+          commands = ["test -f {data_dir}/c-works"]
+
+        [update-repo]
+        "#,
+    );
+    let conf = Config::from_file(config_path.clone()).unwrap();
+    let resume = |uuid: Uuid| RunOptions {
+        resume: Some(uuid),
+        ..Default::default()
+    };
+
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(statuses["packages.b.compilation"], JobStatus::Failed);
+    let storage = storage::start(&conf).await.unwrap();
+    let first_uuid = storage.runs(1).await.unwrap()[0].uuid;
+
+    std::fs::write(format!("{data_dir}/b-works"), "").unwrap();
+    let statuses = run(config_path.clone(), resume(first_uuid)).await.unwrap();
+    assert_eq!(statuses["packages.a.compilation"], JobStatus::Succeeded);
+    assert_eq!(statuses["packages.b.compilation"], JobStatus::Succeeded);
+    assert_eq!(statuses["packages.c.compilation"], JobStatus::Failed);
+    let second_uuid = storage.runs(1).await.unwrap()[0].uuid;
+
+    // resuming the resumed run still counts what succeeded in the first one
+    std::fs::write(format!("{data_dir}/c-works"), "").unwrap();
+    let statuses = run(config_path, resume(second_uuid)).await.unwrap();
+    assert_eq!(statuses["packages.c.compilation"], JobStatus::Succeeded);
+    // a and b already succeeded, so they weren't run again
+    assert_eq!(
+        std::fs::read_to_string(format!("{data_dir}/a-runs")).unwrap(),
+        "a\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(format!("{data_dir}/b-runs")).unwrap(),
+        "b\n"
+    );
+
+    // each resume is its own run, so its duration doesn't include the time in between
+    let runs = storage.runs(10).await.unwrap();
+    assert_eq!(runs.len(), 3);
+    assert_eq!(runs[2].resumed_from, None);
+    assert_eq!(runs[1].resumed_from, Some(first_uuid));
+    assert_eq!(runs[0].resumed_from, Some(second_uuid));
+    assert_eq!(runs[2].status, "failed");
+    assert_eq!(runs[0].status, "succeeded");
+    assert_eq!(runs[0].succeeded, Some(3));
+    assert!(runs[0].start_time >= runs[1].end_time.unwrap());
+    let job_ids: Vec<String> = storage
+        .jobs_in_run(&runs[0].uuid)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|record| record.job_id)
+        .collect();
+    assert_eq!(job_ids, ["packages.c.compilation"]);
+    assert!(matches!(storage.find_run(&Uuid::now_v7()).await, Ok(None)));
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_rebuilt_dependency() {
+    let (data_dir, config_path) = host_run_config(
+        r#"
+        [packages.a]
+          [packages.a.compilation]
+          revision = "1"
+          image = "debian"
+          commands = ["true"]
+          [packages.a.packaging.fedora]
+          image = "debian"
+          commands = ["test ! -f {data_dir}/break-packaging"]
+
+        [update-repo.fedora]
+        image = "debian"
+        commands = ["true"]
+        "#,
+    );
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        statuses["packages.a.packaging.fedora"],
+        JobStatus::Succeeded
+    );
+
+    // the compilation's rebuilt, but packaging it fails
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config.replace(r#"revision = "1""#, r#"revision = "2""#),
+    )
+    .unwrap();
+    std::fs::write(format!("{data_dir}/break-packaging"), "").unwrap();
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(statuses["packages.a.compilation"], JobStatus::Succeeded);
+    assert_eq!(statuses["packages.a.packaging.fedora"], JobStatus::Failed);
+
+    // so it still needs packaging next time, even though the compilation's up-to-date now
+    std::fs::remove_file(format!("{data_dir}/break-packaging")).unwrap();
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        statuses["packages.a.compilation"],
+        JobStatus::Skipped("revision 2 was already built".to_string())
+    );
+    assert_eq!(
+        statuses["packages.a.packaging.fedora"],
+        JobStatus::Succeeded
+    );
+
+    // and after that, both are up-to-date
+    let statuses = run(config_path, RunOptions::default()).await.unwrap();
+    assert_eq!(
+        statuses["packages.a.packaging.fedora"],
+        JobStatus::Skipped("revision 1 was already built".to_string())
+    );
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_retries() {
+    let (data_dir, config_path) = host_run_config(
+        r#"
+        max-jobs = 1
+
+        [packages.a]
+        packaging = {}
+          [packages.a.compilation]
+          image = "debian"
+          retries = 2
+          retry-delay = 1
+          commands = [
+            "if [ -f {data_dir}/tried ]; then echo a2 >> {data_dir}/order; exit 0; fi",
+            "touch {data_dir}/tried",
+            "echo a1 >> {data_dir}/order",
+            "exit 1",
+          ]
+
+        [packages.b]
+        packaging = {}
+          [packages.b.compilation]
+          image = "debian"
+          commands = ["echo b >> {data_dir}/order"]
+
+        [update-repo]
+        "#,
+    );
+
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(statuses["packages.a.compilation"], JobStatus::Succeeded);
+    // b gets a's place while it waits to be retried
+    assert_eq!(
+        std::fs::read_to_string(format!("{data_dir}/order")).unwrap(),
+        "a1\nb\na2\n"
+    );
+
+    let conf = Config::from_file(config_path).unwrap();
+    let storage = storage::start(&conf).await.unwrap();
+    let attempts: Vec<(Option<String>, Option<i16>, Option<Uuid>)> = storage
+        .job_history("packages.a.compilation", 10)
+        .await
+        .unwrap()
+        .into_iter()
+        .rev()
+        .map(|record| (record.status, record.attempt, record.attempt_group))
+        .collect();
+    let group = attempts[0].2;
+    assert_eq!(
+        attempts,
+        [
+            (Some("failed".to_string()), Some(1), group),
+            (Some("succeeded".to_string()), Some(2), group),
+        ]
+    );
+    assert_eq!(storage.runs(1).await.unwrap()[0].status, "succeeded");
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_no_db_path() {
+    let (data_dir, config_path) = host_run_config(
+        r#"
+        [packages.a]
+        packaging = {}
+          [packages.a.compilation]
+          image = "debian"
+          commands = ["true"]
+
+        [update-repo]
+        "#,
+    );
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        format!("{config}path = \"{data_dir}/elsewhere.db\"\n"),
+    )
+    .unwrap();
+    let no_db = RunOptions {
+        no_db: true,
+        ..Default::default()
+    };
+
+    // `path` is the SQLite database, so the JSON lines files go in the default dir instead
+    run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    run(config_path.clone(), no_db.clone()).await.unwrap();
+    assert!(std::path::Path::new(&format!("{data_dir}/elsewhere.db")).is_file());
+    let json_lines = JsonLinesStorage::open(&format!("{data_dir}/no-db")).unwrap();
+    assert_eq!(json_lines.run_records().unwrap().len(), 1);
+
+    // which is where `db import` looks by default
+    db(config_path.clone(), DbCommands::Import { from: None })
+        .await
+        .unwrap();
+    let conf = Config::from_file(config_path.clone()).unwrap();
+    let storage = storage::connect(&conf).await.unwrap();
+    assert_eq!(storage.runs(10).await.unwrap().len(), 2);
+
+    // but with JSON lines as the backend, `path` is kept
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config
+            .replace("\"sqlite\"", "\"json-lines\"")
+            .replace("elsewhere.db", "elsewhere"),
+    )
+    .unwrap();
+    run(config_path, no_db).await.unwrap();
+    let json_lines = JsonLinesStorage::open(&format!("{data_dir}/elsewhere")).unwrap();
+    assert_eq!(json_lines.run_records().unwrap().len(), 1);
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_follow_stops() {
+    let (data_dir, config_path) = host_run_config(
+        r#"
+        [packages.a]
+        packaging = {}
+          [packages.a.compilation]
+          image = "debian"
+          commands = ["true"]
+
+        [update-repo]
+        "#,
+    );
+    let conf = Config::from_file(config_path.clone()).unwrap();
+    let storage = storage::start(&conf).await.unwrap();
+    let mut run = RunRecord::start("hash".to_string(), Trigger::Manual, None);
+    storage.run_started(&run).await.unwrap();
+    let job_uuid = Uuid::now_v7();
+    storage
+        .job_started(&JobStart {
+            uuid: job_uuid,
+            run_uuid: run.uuid,
+            start_time: SystemTime::now(),
+            job_id: "packages.a.compilation".to_string(),
+            revision: "1".to_string(),
+            attempt_group: job_uuid,
+            attempt: 1,
+            log_path: None,
+            image_digest: None,
+        })
+        .await
+        .unwrap();
+
+    // the job never finished, but its run did, so there's nothing more to wait for
+    run.finish(&HashMap::new());
+    run.status = "cancelled".to_string();
+    storage.finish_run(&run).await.unwrap();
+    let follow = logs(
+        config_path,
+        "packages.a.compilation".to_string(),
+        None,
+        None,
+        true,
+        None,
+    );
+    tokio::time::timeout(std::time::Duration::from_secs(5), follow)
+        .await
+        .unwrap()
+        .unwrap();
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_close_runs() {
+    let (data_dir, config_path) = host_run_config("[packages]\n[update-repo]");
+    let conf = Config::from_file(config_path).unwrap();
+    let storage = storage::start(&conf).await.unwrap();
+    let cancellation = Cancellation::default();
+    let open = RunRecord::start("hash".to_string(), Trigger::Manual, None);
+    let finished = RunRecord::start("hash".to_string(), Trigger::Manual, None);
+    for run in [&open, &finished] {
+        storage.run_started(run).await.unwrap();
+        cancellation.run_started(storage.clone(), run);
+    }
+    cancellation.run_finished(&finished.uuid);
+
+    // only what's still going is marked as cancelled when exiting immediately
+    cancellation.close_runs().await;
+    let closed = storage.find_run(&open.uuid).await.unwrap().unwrap();
+    assert_eq!(closed.status, "cancelled");
+    assert!(closed.end_time.is_some());
+    let untouched = storage.find_run(&finished.uuid).await.unwrap().unwrap();
+    assert_eq!(untouched.status, "running");
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_distro_named_package() {
+    let (data_dir, config_path) = host_run_config(
+        r#"
+        [packages.fedora]
+        version-check = ["echo 1.0"]
+          [packages.fedora.compilation]
+          image = "debian"
+          commands = ["true"]
+          [packages.fedora.packaging.fedora]
+          image = "debian"
+          commands = ["true"]
+
+        [update-repo.fedora]
+        image = "debian"
+        commands = ["true"]
+        "#,
+    );
+
+    let statuses = run(config_path.clone(), RunOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(statuses["update-repo.fedora"], JobStatus::Succeeded);
+    let conf = Config::from_file(config_path).unwrap();
+    let storage = storage::start(&conf).await.unwrap();
+    let compilation = storage
+        .job_history("packages.fedora.compilation", 1)
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(compilation.package_version.as_deref(), Some("1.0"));
+    // the package's version isn't the update-repo job's, despite the name
+    let update_repo = storage
+        .job_history("update-repo.fedora", 1)
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(update_repo.package_version, None);
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
@@ -0,0 +1,427 @@
+//! Checking the config for problems before anything's run, with where in the file they are - see [`validate`]
+//!
+//! This works on the TOML document itself rather than [`Config`], so it can point at the exact line and column, and still find everything else if some of it doesn't deserialize.
+
+use crate::data::{self, Config};
+use crate::errors::Error;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::Range;
+use toml_edit::{ImDocument, Item, TableLike, Value};
+
+/// The keys allowed at the top level of the config
+const CONFIG_KEYS: &[&str] = &[
+    "max-jobs",
+    "max-threads",
+    "data-dir",
+    "log-files",
+    "schedule",
+    "timeout",
+    "runtime",
+    "packages",
+    "update-repo",
+    "volumes",
+    "hooks",
+    "database",
+];
+/// The keys allowed in `[packages.*]`
+const PACKAGE_KEYS: &[&str] = &[
+    "dependencies",
+    "version-check",
+    "version_check",
+    "schedule",
+    "compilation",
+    "packaging",
+];
+/// The keys allowed in a job
+const JOB_KEYS: &[&str] = &[
+    "revision",
+    "threads",
+    "image",
+    "commands",
+    "volumes",
+    "privileged",
+    "shell",
+    "runtime",
+    "timeout",
+    "retries",
+    "retry-delay",
+];
+/// The keys allowed in `[hooks]`
+const HOOKS_KEYS: &[&str] = &[
+    "before-job",
+    "after-job",
+    "on-job-failure",
+    "after-update-repo",
+    "on-run-complete",
+];
+/// The keys allowed in `[database]`
+const DATABASE_KEYS: &[&str] = &[
+    "backend",
+    "path",
+    "url",
+    "host",
+    "port",
+    "user",
+    "password-file",
+    "dbname",
+    "sslmode",
+];
+
+/// Each package's (known) dependencies, with where they are in the file
+type Dependencies<'a> = HashMap<&'a str, Vec<(&'a str, Option<Range<usize>>)>>;
+
+/// How bad a problem is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Severity {
+    /// Jobs won't be run until it's fixed
+    Error,
+    /// Probably a mistake, but it's still fine to run
+    Warning,
+}
+
+/// A problem with the config
+#[derive(Debug, Clone)]
+pub(crate) struct Diagnostic {
+    pub(crate) severity: Severity,
+    pub(crate) message: String,
+    /// Where in the file it is, in bytes
+    pub(crate) span: Option<Range<usize>>,
+}
+
+/// Checks a config for problems, returning all of them in the order they're in the file
+pub(crate) fn validate(source: &str) -> Vec<Diagnostic> {
+    let doc = match ImDocument::parse(source) {
+        Ok(doc) => doc,
+        Err(e) => {
+            return vec![Diagnostic {
+                severity: Severity::Error,
+                message: e.message().trim().to_string(),
+                span: e.span(),
+            }];
+        }
+    };
+
+    let mut validator = Validator::default();
+    if let Err(e) = toml::from_str::<Config>(source) {
+        validator.error(e.message().trim().to_string(), e.span());
+    }
+    validator.check_config(doc.as_table());
+
+    let mut diagnostics = validator.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.as_ref().map(|span| span.start));
+    return diagnostics;
+}
+
+/// Checks the config file at `config_path`, printing any problems, and errors if there are any that aren't just warnings
+///
+/// This is done before every run
+pub(crate) fn check(config_path: &str) -> Result<Vec<Diagnostic>, Error> {
+    let source = fs::read_to_string(config_path)?;
+    let diagnostics = validate(&source);
+    for diagnostic in &diagnostics {
+        eprintln!("{}\n", format_diagnostic(config_path, &source, diagnostic));
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(Error::InvalidConfig(errors));
+    }
+    return Ok(diagnostics);
+}
+
+/// Formats a diagnostic with where it is and the line it's on, like:
+///
+/// ```txt
+/// error: unknown volume `librewolf`; it's not in `[volumes]`
+///   --> gregory.toml:15:16
+///    |
+/// 15 |     volumes = ["librewolf"]
+///    |                ^^^^^^^^^^^
+/// ```
+pub(crate) fn format_diagnostic(path: &str, source: &str, diagnostic: &Diagnostic) -> String {
+    let severity = match diagnostic.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+    let span = match &diagnostic.span {
+        Some(span) => span.clone(),
+        None => return format!("{severity}: {}\n  --> {path}", diagnostic.message),
+    };
+
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |i| start + i);
+    let line_number = source[..start].matches('\n').count() + 1;
+    let column = source[line_start..start].chars().count() + 1;
+    let line = source[line_start..line_end].trim_end_matches('\r');
+    let underline_length = source[start..span.end.clamp(start, line_end)]
+        .chars()
+        .count()
+        .max(1);
+
+    let gutter = " ".repeat(line_number.to_string().len());
+    return format!(
+        "{severity}: {}\n{gutter}--> {path}:{line_number}:{column}\n{gutter} |\n{line_number} | {line}\n{gutter} | {}{}",
+        diagnostic.message,
+        " ".repeat(column - 1),
+        "^".repeat(underline_length),
+    );
+}
+
+/// Collects the problems while going through the config
+#[derive(Default)]
+struct Validator {
+    diagnostics: Vec<Diagnostic>,
+    /// The names in `[volumes]`
+    volumes: HashSet<String>,
+    /// `max-threads`, or the default for this machine
+    max_threads: f32,
+}
+
+impl Validator {
+    fn error(&mut self, message: String, span: Option<Range<usize>>) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message,
+            span,
+        });
+    }
+
+    fn warning(&mut self, message: String, span: Option<Range<usize>>) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            message,
+            span,
+        });
+    }
+
+    /// Errors on any keys in `table` that aren't in `known`; `name` is what the table is, for the message
+    fn check_keys(&mut self, table: &dyn TableLike, known: &[&str], name: &str) {
+        for (key, _) in table.iter() {
+            if !known.contains(&key) {
+                let span = table.key(key).and_then(|key| key.span());
+                self.error(format!("unknown key `{key}` in {name}"), span);
+            }
+        }
+    }
+
+    fn check_config(&mut self, config: &dyn TableLike) {
+        self.check_keys(config, CONFIG_KEYS, "the config");
+
+        self.volumes = config
+            .get("volumes")
+            .and_then(Item::as_table_like)
+            .map(|volumes| volumes.iter().map(|(name, _)| name.to_string()).collect())
+            .unwrap_or_default();
+        self.max_threads = config
+            .get("max-threads")
+            .and_then(number)
+            .unwrap_or_else(data::max_threads);
+
+        let update_repo: HashSet<String> =
+            match config.get("update-repo").and_then(Item::as_table_like) {
+                Some(update_repo) => {
+                    for (distro, job) in update_repo.iter() {
+                        if let Some(job) = job.as_table_like() {
+                            self.check_job(job, &format!("`[update-repo.{distro}]`"));
+                        }
+                    }
+                    update_repo
+                        .iter()
+                        .map(|(distro, _)| distro.to_string())
+                        .collect()
+                }
+                None => HashSet::new(),
+            };
+
+        if let Some(packages) = config.get("packages").and_then(Item::as_table_like) {
+            for (package_name, package) in packages.iter() {
+                if let Some(package) = package.as_table_like() {
+                    self.check_package(package_name, package, packages, &update_repo);
+                }
+            }
+            self.check_cycles(packages);
+        }
+
+        if let Some(hooks) = config.get("hooks").and_then(Item::as_table_like) {
+            self.check_hooks(hooks);
+        }
+        if let Some(database) = config.get("database").and_then(Item::as_table_like) {
+            self.check_keys(database, DATABASE_KEYS, "`[database]`");
+        }
+    }
+
+    fn check_package(
+        &mut self,
+        package_name: &str,
+        package: &dyn TableLike,
+        packages: &dyn TableLike,
+        update_repo: &HashSet<String>,
+    ) {
+        self.check_keys(
+            package,
+            PACKAGE_KEYS,
+            &format!("`[packages.{package_name}]`"),
+        );
+
+        for (dependency, span) in strings(package.get("dependencies")) {
+            if !packages.contains_key(dependency) {
+                self.error(
+                    format!(
+                        "unknown dependency `{dependency}`; there's no `[packages.{dependency}]`"
+                    ),
+                    span,
+                );
+            }
+        }
+
+        if let Some(compilation) = package.get("compilation").and_then(Item::as_table_like) {
+            self.check_job(
+                compilation,
+                &format!("`[packages.{package_name}.compilation]`"),
+            );
+        }
+        if let Some(packaging) = package.get("packaging").and_then(Item::as_table_like) {
+            for (distro, job) in packaging.iter() {
+                if let Some(job) = job.as_table_like() {
+                    self.check_job(
+                        job,
+                        &format!("`[packages.{package_name}.packaging.{distro}]`"),
+                    );
+                }
+                if !update_repo.contains(distro) {
+                    self.warning(
+                        format!("there's no `[update-repo.{distro}]`, so nothing will update the {distro} repo after this is packaged"),
+                        packaging.key(distro).and_then(|key| key.span()),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Checks a job's keys, volumes, and threads
+    fn check_job(&mut self, job: &dyn TableLike, name: &str) {
+        self.check_keys(job, JOB_KEYS, name);
+
+        for (volume, span) in strings(job.get("volumes")) {
+            if !self.volumes.contains(volume) {
+                self.error(
+                    format!("unknown volume `{volume}`; it's not in `[volumes]`"),
+                    span,
+                );
+            }
+        }
+
+        if let Some((threads, span)) = job
+            .get("threads")
+            .and_then(|threads| Some((number(threads)?, threads.span())))
+        {
+            if threads > self.max_threads {
+                self.warning(
+                    format!(
+                        "`threads` is more than `max-threads` ({}), so it'll be limited to that",
+                        self.max_threads
+                    ),
+                    span,
+                );
+            }
+        }
+    }
+
+    /// Checks the jobs in hooks - hooks that are just commands don't have anything to check
+    fn check_hooks(&mut self, hooks: &dyn TableLike) {
+        self.check_keys(hooks, HOOKS_KEYS, "`[hooks]`");
+
+        for (event, hook_list) in hooks.iter() {
+            let name = format!("a `{event}` hook");
+            if let Some(tables) = hook_list.as_array_of_tables() {
+                for table in tables.iter() {
+                    self.check_job(table, &name);
+                }
+            } else if let Some(array) = hook_list.as_array() {
+                for hook in array.iter() {
+                    if let Value::InlineTable(table) = hook {
+                        self.check_job(table, &name);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Errors on any cycles in the packages' `dependencies`, pointing at the dependency that completes the cycle
+    fn check_cycles(&mut self, packages: &dyn TableLike) {
+        let mut dependencies: Dependencies = HashMap::new();
+        for (package_name, package) in packages.iter() {
+            dependencies.insert(
+                package_name,
+                strings(package.get("dependencies"))
+                    .into_iter()
+                    .filter(|(dependency, _)| packages.contains_key(dependency))
+                    .collect(),
+            );
+        }
+
+        // packages that have been fully checked
+        let mut done: HashSet<&str> = HashSet::new();
+        for (package_name, _) in packages.iter() {
+            let mut path: Vec<&str> = Vec::new();
+            self.find_cycles(package_name, &dependencies, &mut path, &mut done);
+        }
+    }
+
+    /// Goes through everything `package_name` depends on, depth-first, erroring when it gets back to something in `path`
+    fn find_cycles<'a>(
+        &mut self,
+        package_name: &'a str,
+        dependencies: &Dependencies<'a>,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) {
+        if done.contains(package_name) {
+            return;
+        }
+        path.push(package_name);
+        for (dependency, span) in &dependencies[package_name] {
+            if let Some(i) = path.iter().position(|p| p == dependency) {
+                let cycle: Vec<String> = path[i..]
+                    .iter()
+                    .chain([dependency])
+                    .map(|p| format!("packages.{p}"))
+                    .collect();
+                self.error(
+                    format!("dependency cycle detected: {}", cycle.join(" -> ")),
+                    span.clone(),
+                );
+            } else {
+                self.find_cycles(dependency, dependencies, path, done);
+            }
+        }
+        path.pop();
+        done.insert(package_name);
+    }
+}
+
+/// Returns the strings in an array, with where each one is; anything else is left for deserializing to complain about
+fn strings(item: Option<&Item>) -> Vec<(&str, Option<Range<usize>>)> {
+    return match item.and_then(Item::as_array) {
+        Some(array) => array
+            .iter()
+            .filter_map(|value| Some((value.as_str()?, value.span())))
+            .collect(),
+        None => Vec::new(),
+    };
+}
+
+/// Returns an integer or float as an `f32`
+fn number(item: &Item) -> Option<f32> {
+    return item
+        .as_float()
+        .map(|number| number as f32)
+        .or_else(|| item.as_integer().map(|number| number as f32));
+}